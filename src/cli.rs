@@ -0,0 +1,517 @@
+use std::{
+    error::Error,
+    fmt,
+    fs::{self, OpenOptions},
+    io::{self, BufRead, IsTerminal, Write},
+    ops::Bound,
+    process::ExitCode,
+};
+
+use random::Source;
+
+use crate::types::{
+    cache::CacheConfig,
+    file_store::Durability,
+    node_store::DEFAULT_BLOCK_SIZE,
+    tree::{Tree, TreeError},
+};
+
+type Key = i64;
+type Value = String;
+
+const USAGE: &str = "usage: bplus-tree <command> <tree file> [arguments]
+
+commands:
+  create <file> [--shadow] [--block-size <bytes>] [--force]
+                               create an empty tree, using shadow paging instead of a log with
+                               --shadow and blocks of the given size (4096 by default). An
+                               existing file is only replaced with --force
+  insert <file> <key> <value>  add an entry, fails if the key is already present
+  get <file> <key>             print the value stored for the key
+  delete <file> <key>          remove the entry with the key
+  scan <file> [from] [to]      print the entries with keys from `from` up to and including `to`
+  fill <file> <count> [seed]   insert random entries
+  dump-dot <file>              print the nodes in the DOT format
+  stats <file>                 print the shape and size of the tree
+  verify <file>                check the tree against its invariants
+  repl <file>                  run commands against the tree interactively";
+
+const REPL_HELP: &str = "commands: insert <key> <value>, get <key>, delete <key>, scan [from] [to],
+          fill <count> [seed], dump-dot, stats, verify, help, quit";
+
+/// Errors ending a command of the command line tool
+#[derive(Debug)]
+pub enum CliError {
+    /// the command or its arguments are invalid, holds the usage to show
+    Usage(String),
+    Tree(TreeError),
+    /// `verify` found violations, holds their amount
+    Violations(usize),
+    /// `create` would have replaced the file at the path
+    FileExists(String),
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CliError::Usage(usage) => write!(f, "{usage}"),
+            CliError::Tree(e) => write!(f, "error: {e}"),
+            CliError::Violations(n) => write!(f, "the tree has {n} violations"),
+            CliError::FileExists(path) => {
+                write!(f, "{path} already exists, use --force to replace it")
+            }
+        }
+    }
+}
+
+impl Error for CliError {}
+
+impl From<TreeError> for CliError {
+    fn from(e: TreeError) -> Self {
+        CliError::Tree(e)
+    }
+}
+
+impl From<io::Error> for CliError {
+    fn from(e: io::Error) -> Self {
+        CliError::Tree(e.into())
+    }
+}
+
+impl CliError {
+    pub fn exit_code(&self) -> ExitCode {
+        match self {
+            CliError::Usage(_) => ExitCode::from(2),
+            _ => ExitCode::FAILURE,
+        }
+    }
+
+    /// whether the output was closed by its reader, e.g. by `head` in a pipe. That is not a
+    /// failure of the command.
+    pub fn is_broken_pipe(&self) -> bool {
+        matches!(self, CliError::Tree(TreeError::IOError(e)) if e.kind() == io::ErrorKind::BrokenPipe)
+    }
+}
+
+/// a command operating on an open tree
+enum Command {
+    Insert { key: Key, value: Value },
+    Get { key: Key },
+    Delete { key: Key },
+    Scan { from: Option<Key>, to: Option<Key> },
+    Fill { count: usize, seed: u64 },
+    DumpDot,
+    Stats,
+    Verify,
+}
+
+fn parse_arg<A: std::str::FromStr>(arg: &str, usage: &str) -> Result<A, CliError> {
+    arg.parse()
+        .map_err(|_| CliError::Usage(format!("invalid argument '{arg}', usage: {usage}")))
+}
+
+impl Command {
+    /// parses the command and its arguments, which don't include the tree file
+    fn parse(name: &str, args: &[String]) -> Result<Self, CliError> {
+        // least and most arguments each command takes
+        let (usage, min, max) = match name {
+            "insert" => ("insert <key> <value>", 2, usize::MAX),
+            "get" => ("get <key>", 1, 1),
+            "delete" => ("delete <key>", 1, 1),
+            "scan" => ("scan [from] [to]", 0, 2),
+            "fill" => ("fill <count> [seed]", 1, 2),
+            "dump-dot" => ("dump-dot", 0, 0),
+            "stats" => ("stats", 0, 0),
+            "verify" => ("verify", 0, 0),
+            name => {
+                return Err(CliError::Usage(format!(
+                    "unknown command '{name}'\n\n{USAGE}"
+                )))
+            }
+        };
+
+        if args.len() < min || args.len() > max {
+            return Err(CliError::Usage(format!("usage: {usage}")));
+        }
+
+        Ok(match name {
+            // values may contain spaces when they are typed into the repl
+            "insert" => Command::Insert {
+                key: parse_arg(&args[0], usage)?,
+                value: args[1..].join(" "),
+            },
+            "get" => Command::Get {
+                key: parse_arg(&args[0], usage)?,
+            },
+            "delete" => Command::Delete {
+                key: parse_arg(&args[0], usage)?,
+            },
+            "scan" => Command::Scan {
+                from: args.first().map(|a| parse_arg(a, usage)).transpose()?,
+                to: args.get(1).map(|a| parse_arg(a, usage)).transpose()?,
+            },
+            "fill" => Command::Fill {
+                count: parse_arg(&args[0], usage)?,
+                seed: args.get(1).map_or(Ok(0), |a| parse_arg(a, usage))?,
+            },
+            "dump-dot" => Command::DumpDot,
+            "stats" => Command::Stats,
+            _ => Command::Verify,
+        })
+    }
+
+    /// runs the command, writing what it prints to `out`
+    fn run(
+        self,
+        tree: &mut Tree<Key, Value>,
+        path: &str,
+        out: &mut impl Write,
+    ) -> Result<(), CliError> {
+        match self {
+            Command::Insert { key, value } => tree.insert(key, value)?,
+            Command::Get { key } => match tree.search(&key)? {
+                Some(value) => writeln!(out, "{value}")?,
+                None => writeln!(out, "key {key} not found")?,
+            },
+            Command::Delete { key } => {
+                if tree.remove(&key)?.is_none() {
+                    writeln!(out, "key {key} not found")?;
+                }
+            }
+            Command::Scan { from, to } => {
+                let from = from.map_or(Bound::Unbounded, Bound::Included);
+                let to = to.map_or(Bound::Unbounded, Bound::Included);
+
+                for entry in tree.range((from, to)) {
+                    let (key, value) = entry?;
+                    writeln!(out, "{key}\t{value}")?;
+                }
+            }
+            Command::Fill { count, seed } => {
+                let mut source = random::default(seed);
+                tree.transaction(|tree| {
+                    for i in 0..count {
+                        let key = source.read_u64() as Key;
+                        tree.upsert(key, format!("value {i}"))?;
+                    }
+                    Ok(())
+                })?;
+            }
+            Command::DumpDot => tree.print_graphviz(out)?,
+            Command::Stats => {
+                let report = tree.verify()?;
+                writeln!(out, "entries          {}", report.entries)?;
+                writeln!(out, "depth            {}", report.depth)?;
+                writeln!(out, "inner nodes      {}", report.inner_nodes)?;
+                writeln!(out, "leaves           {}", report.leaves)?;
+                writeln!(out, "overflow blocks  {}", report.overflow_blocks)?;
+                writeln!(out, "free blocks      {}", report.free_blocks)?;
+                writeln!(out, "file size        {} bytes", fs::metadata(path)?.len())?;
+            }
+            Command::Verify => {
+                let report = tree.verify()?;
+                write!(out, "{report}")?;
+                if !report.is_ok() {
+                    return Err(CliError::Violations(report.violations.len()));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// reads commands from `input` and runs them against the tree until the input ends. A prompt is
+/// only shown if the input is `interactive`.
+fn repl(
+    mut tree: Tree<Key, Value>,
+    path: &str,
+    input: impl BufRead,
+    interactive: bool,
+    out: &mut impl Write,
+) -> Result<(), CliError> {
+    let mut lines = input.lines();
+
+    loop {
+        if interactive {
+            write!(out, "> ")?;
+            out.flush()?;
+        }
+
+        let Some(line) = lines.next() else {
+            break;
+        };
+        let words: Vec<String> = line?.split_whitespace().map(String::from).collect();
+
+        let result = match words.first().map(String::as_str) {
+            None => Ok(()),
+            Some("quit" | "exit") => break,
+            Some("help") => writeln!(out, "{REPL_HELP}").map_err(CliError::from),
+            Some(name) => {
+                Command::parse(name, &words[1..]).and_then(|c| c.run(&mut tree, path, out))
+            }
+        };
+
+        // a failing command does not end the session, unless nobody reads its output anymore
+        match result {
+            Err(e) if e.is_broken_pipe() => return Err(e),
+            Err(e) => eprintln!("{e}"),
+            Ok(()) => (),
+        }
+    }
+
+    Ok(())
+}
+
+/// runs the command line tool with the given arguments, not including the program name, writing
+/// what the command prints to `out`
+pub fn run(args: &[String], out: &mut impl Write) -> Result<(), CliError> {
+    let [name, path, args @ ..] = args else {
+        return Err(CliError::Usage(USAGE.to_string()));
+    };
+
+    match name.as_str() {
+        "create" => {
+            let usage = "create <file> [--shadow] [--block-size <bytes>] [--force]";
+            let mut durability = Durability::WriteAheadLog;
+            let mut block_size = DEFAULT_BLOCK_SIZE;
+            let mut force = false;
+
+            let mut flags = args.iter();
+            while let Some(flag) = flags.next() {
+                match flag.as_str() {
+                    "--shadow" => durability = Durability::ShadowPaging,
+                    "--block-size" => {
+                        let size = flags
+                            .next()
+                            .ok_or_else(|| CliError::Usage(format!("usage: {usage}")))?;
+                        block_size = parse_arg(size, usage)?;
+                    }
+                    "--force" => force = true,
+                    _ => return Err(CliError::Usage(format!("usage: {usage}"))),
+                }
+            }
+
+            // creating the tree truncates the file, so it has to be new unless replacing it was
+            // asked for
+            if !force {
+                OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .open(path)
+                    .map_err(|e| match e.kind() {
+                        io::ErrorKind::AlreadyExists => CliError::FileExists(path.clone()),
+                        _ => e.into(),
+                    })?;
+            }
+            Tree::<Key, Value>::with_durability(
+                path.clone(),
+                block_size,
+                durability,
+                CacheConfig::default(),
+            )?;
+        }
+        "repl" if args.is_empty() => {
+            let tree = Tree::load(path.clone(), CacheConfig::default())?;
+            let stdin = io::stdin();
+            let interactive = stdin.is_terminal();
+            repl(tree, path, stdin.lock(), interactive, out)?
+        }
+        "repl" => return Err(CliError::Usage("usage: repl <file>".to_string())),
+        name => {
+            let command = Command::parse(name, args)?;
+            command.run(
+                &mut Tree::load(path.clone(), CacheConfig::default())?,
+                path,
+                out,
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::types::test_util::TempFile;
+
+    fn words(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    /// runs `<command> <file> <arguments>`, returning what it printed
+    fn cli(path: &str, line: &str) -> Result<String, CliError> {
+        let mut args = words(line);
+        args.insert(1, path.to_string());
+
+        let mut out = Vec::new();
+        run(&args, &mut out)?;
+        Ok(String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn commands_are_parsed_with_their_arguments() {
+        let parse = |line: &str| {
+            let words = words(line);
+            Command::parse(&words[0], &words[1..]).unwrap()
+        };
+
+        assert!(matches!(
+            parse("insert -1 two words"),
+            Command::Insert { key: -1, value } if value == "two words"
+        ));
+        assert!(matches!(parse("get 3"), Command::Get { key: 3 }));
+        assert!(matches!(parse("delete 4"), Command::Delete { key: 4 }));
+        assert!(matches!(
+            parse("scan"),
+            Command::Scan {
+                from: None,
+                to: None
+            }
+        ));
+        assert!(matches!(
+            parse("scan -5 5"),
+            Command::Scan {
+                from: Some(-5),
+                to: Some(5)
+            }
+        ));
+        assert!(matches!(
+            parse("fill 10"),
+            Command::Fill { count: 10, seed: 0 }
+        ));
+        assert!(matches!(
+            parse("fill 10 7"),
+            Command::Fill { count: 10, seed: 7 }
+        ));
+        assert!(matches!(parse("dump-dot"), Command::DumpDot));
+        assert!(matches!(parse("stats"), Command::Stats));
+        assert!(matches!(parse("verify"), Command::Verify));
+    }
+
+    #[test]
+    fn invalid_arguments_are_usage_errors() {
+        for line in [
+            "insert",
+            "insert 1",
+            "insert one value",
+            "get",
+            "get 1 2",
+            "get one",
+            "delete",
+            "delete 1 2",
+            "scan a",
+            "scan 1 2 3",
+            "fill",
+            "fill -1",
+            "fill 1 2 3",
+            "dump-dot 1",
+            "stats 1",
+            "verify 1",
+            "unknown",
+        ] {
+            let words = words(line);
+            let result = Command::parse(&words[0], &words[1..]);
+            assert!(matches!(result, Err(CliError::Usage(_))), "{line}");
+        }
+
+        // none of these get as far as touching the file
+        let file = TempFile::new("cli-usage");
+        for line in [
+            "create --block-size",
+            "create --block-size large",
+            "create --unknown",
+            "repl extra",
+        ] {
+            let result = cli(&file.path(), line);
+            assert!(matches!(result, Err(CliError::Usage(_))), "{line}");
+        }
+        assert!(!fs::exists(file.path()).unwrap());
+        for args in [vec![], vec!["create".to_string()]] {
+            let error = run(&args, &mut Vec::new()).unwrap_err();
+            assert!(matches!(error, CliError::Usage(_)));
+            assert_eq!(error.exit_code(), ExitCode::from(2));
+        }
+    }
+
+    #[test]
+    fn commands_change_and_print_the_tree() {
+        let file = TempFile::new("cli");
+        let path = file.path();
+
+        assert_eq!(cli(&path, "create --block-size 128").unwrap(), "");
+        assert_eq!(cli(&path, "insert 5 five").unwrap(), "");
+        assert_eq!(cli(&path, "insert -3 minus three").unwrap(), "");
+        assert_eq!(cli(&path, "insert 12 twelve").unwrap(), "");
+        assert!(matches!(
+            cli(&path, "insert 5 again"),
+            Err(CliError::Tree(TreeError::DuplicateKey))
+        ));
+
+        assert_eq!(cli(&path, "get 5").unwrap(), "five\n");
+        assert_eq!(cli(&path, "get 7").unwrap(), "key 7 not found\n");
+        assert_eq!(
+            cli(&path, "scan").unwrap(),
+            "-3\tminus three\n5\tfive\n12\ttwelve\n"
+        );
+        assert_eq!(cli(&path, "scan 0").unwrap(), "5\tfive\n12\ttwelve\n");
+        assert_eq!(
+            cli(&path, "scan -3 5").unwrap(),
+            "-3\tminus three\n5\tfive\n"
+        );
+
+        assert_eq!(cli(&path, "delete 5").unwrap(), "");
+        assert_eq!(cli(&path, "delete 5").unwrap(), "key 5 not found\n");
+        assert_eq!(cli(&path, "get 5").unwrap(), "key 5 not found\n");
+
+        cli(&path, "fill 300").unwrap();
+        assert!(cli(&path, "stats")
+            .unwrap()
+            .starts_with("entries          302\n"));
+        cli(&path, "verify").unwrap();
+        let dot = cli(&path, "dump-dot").unwrap();
+        assert!(
+            dot.starts_with("digraph G {\n") && dot.ends_with("}\n"),
+            "{dot}"
+        );
+    }
+
+    #[test]
+    fn create_keeps_existing_files() {
+        let file = TempFile::new("cli-exists");
+        let path = file.path();
+        cli(&path, "create").unwrap();
+        cli(&path, "insert 1 one").unwrap();
+
+        assert!(matches!(
+            cli(&path, "create --shadow"),
+            Err(CliError::FileExists(_))
+        ));
+        assert_eq!(cli(&path, "get 1").unwrap(), "one\n");
+
+        cli(&path, "create --force").unwrap();
+        assert_eq!(cli(&path, "get 1").unwrap(), "key 1 not found\n");
+    }
+
+    #[test]
+    fn repl_runs_commands_until_quit() {
+        let file = TempFile::new("cli-repl");
+        let path = file.path();
+        cli(&path, "create").unwrap();
+
+        // the failing commands are reported on stderr and don't end the session
+        let input = Cursor::new("insert 1 a b\n\nget 1\nget x\nunknown\nhelp\nquit\nget 1\n");
+        let tree = Tree::load(path.clone(), CacheConfig::default()).unwrap();
+        let mut out = Vec::new();
+        repl(tree, &path, input, false, &mut out).unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            format!("a b\n{REPL_HELP}\n")
+        );
+        assert_eq!(cli(&path, "get 1").unwrap(), "a b\n");
+    }
+}
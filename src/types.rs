@@ -1,5 +1,15 @@
+pub mod bulk_load;
+pub mod cache;
+pub mod cache_policy;
+pub mod entry;
 pub mod file_store;
 pub mod node;
 pub mod node_store;
-pub mod second_chance_cache;
+pub mod page_table;
+pub mod range;
+pub mod snapshot;
+#[cfg(test)]
+pub mod test_util;
 pub mod tree;
+pub mod verify;
+pub mod wal;
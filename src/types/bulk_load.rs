@@ -0,0 +1,430 @@
+use std::{cmp::Ordering, collections::VecDeque, mem};
+
+use super::{
+    file_store::{
+        inner_cell_size, leaf_cell_size, payload_size, FileStore, INNER_HEADER_SIZE,
+        LEAF_HEADER_SIZE, OVERFLOW_REF_SIZE,
+    },
+    node::{InnerNode, LeafNode, LeafValue, Node, NodeIdent, SearchKey, Value},
+    node_store::NodeStore,
+    tree::TreeError,
+};
+
+/// Groups the entries of one level of the tree into nodes from left to right, filling every node
+/// up to a target amount of bytes. The last two nodes are held back until the level is complete,
+/// so the last one can be rebalanced with its neighbour instead of ending up underfull.
+struct Level<E> {
+    // nodes that may still change, with the size of each of their entries
+    nodes: VecDeque<Vec<(E, usize)>>,
+    target: usize,
+    capacity: usize,
+    // the first entry of an inner node is its leftmost child, which is stored in the header
+    first_free: bool,
+}
+
+/// amount of bytes the entries take up in their node
+fn node_size<E>(entries: &[(E, usize)], first_free: bool) -> usize {
+    entries
+        .iter()
+        .skip(first_free as usize)
+        .map(|(_, size)| size)
+        .sum()
+}
+
+fn strip_sizes<E>(entries: Vec<(E, usize)>) -> Vec<E> {
+    entries.into_iter().map(|(entry, _)| entry).collect()
+}
+
+impl<E> Level<E> {
+    fn new(header_size: usize, block_size: usize, fill_factor: f64, first_free: bool) -> Self {
+        let capacity = payload_size(block_size) - header_size;
+
+        Level {
+            nodes: VecDeque::new(),
+            target: (capacity as f64 * fill_factor) as usize,
+            capacity,
+            first_free,
+        }
+    }
+
+    /// adds the next entry, returning the entries of a node that is complete
+    fn push(&mut self, entry: E, size: usize) -> Option<Vec<E>> {
+        match self.nodes.back_mut() {
+            Some(node) if node_size(node, self.first_free) + size <= self.target => {
+                node.push((entry, size));
+                return None;
+            }
+            _ => self.nodes.push_back(vec![(entry, size)]),
+        }
+
+        if self.nodes.len() > 2 {
+            return self.nodes.pop_front().map(strip_sizes);
+        }
+        None
+    }
+
+    /// returns the remaining nodes, moving entries into the last one if it would underflow
+    fn finish(&mut self) -> Vec<Vec<E>> {
+        let underflows =
+            self.nodes.len() == 2 && node_size(&self.nodes[1], self.first_free) < self.capacity / 4;
+        if !underflows {
+            return self.nodes.drain(..).map(strip_sizes).collect();
+        }
+
+        let mut entries: Vec<(E, usize)> = self.nodes.drain(..).flatten().collect();
+        let total = node_size(&entries, self.first_free);
+        if total <= self.capacity {
+            return vec![strip_sizes(entries)];
+        }
+
+        // split the entries of both nodes in half, as if a single node overflowed. The first entry
+        // of the right node is free as well, so both halves are measured on their own.
+        let split_at = (1..entries.len())
+            .find(|&index| {
+                node_size(&entries[..index], self.first_free)
+                    >= node_size(&entries[index..], self.first_free)
+            })
+            .unwrap_or(entries.len() - 1);
+        let right = entries.split_off(split_at);
+
+        vec![strip_sizes(entries), strip_sizes(right)]
+    }
+}
+
+/// blocks staged by the loader after which they are committed, so the blocks of a large tree
+/// are not all held in memory at once
+const COMMIT_BLOCKS: NodeIdent = 256;
+
+/// Writes the nodes of a tree holding a sorted stream of entries to an empty store, one level at
+/// a time from the leaves up. Blocks are allocated in the order they are written, each leaf
+/// followed by the overflow blocks of its values, so the leaves end up in ascending blocks.
+struct Loader<'a, K, T>
+where
+    K: SearchKey,
+    T: Value,
+{
+    store: &'a mut FileStore<K, T>,
+    fill_factor: f64,
+    leaves: Level<(K, T)>,
+    last_key: Option<K>,
+    // last leaf written, held back until the next leaf is allocated so it can link to it
+    last_leaf: Option<(NodeIdent, LeafNode<K, T>)>,
+    // blocks allocated when the staged blocks were last committed
+    committed: NodeIdent,
+    // smallest key and block of every node of the level written last
+    written: Vec<(K, NodeIdent)>,
+}
+
+impl<K, T> Loader<'_, K, T>
+where
+    K: SearchKey,
+    T: Value,
+{
+    fn push(&mut self, key: K, value: T) -> Result<(), TreeError> {
+        match self.last_key.as_ref().map(|last| key.cmp(last)) {
+            Some(Ordering::Less) => return Err(TreeError::Unsorted),
+            Some(Ordering::Equal) => return Err(TreeError::DuplicateKey),
+            _ => {}
+        }
+        self.last_key = Some(key.clone());
+
+        // the value is only moved to overflow blocks once its leaf is written
+        let value_size = match LeafValue::stays_inline(&key, &value, self.store.block_size()) {
+            Some(true) => value.encoded_size(),
+            Some(false) => OVERFLOW_REF_SIZE,
+            None => return Err(TreeError::KeyTooLarge),
+        };
+        let size = leaf_cell_size(key.encoded_size(), value_size);
+
+        if let Some(entries) = self.leaves.push((key, value), size) {
+            self.write_leaf(entries)?;
+        }
+
+        Ok(())
+    }
+
+    /// writes the remaining leaves and the inner levels above them, returns the root
+    fn finish(mut self) -> Result<NodeIdent, TreeError> {
+        // a tree without entries consists of a single empty leaf
+        let leaves = self.leaves.finish();
+        if leaves.is_empty() {
+            self.write_leaf(vec![])?;
+        }
+        for entries in leaves {
+            self.write_leaf(entries)?;
+        }
+        let mut last = 0;
+        if let Some((ident, leaf)) = self.last_leaf.take() {
+            self.stage(ident, Node::Leaf(leaf))?;
+            last = ident;
+        }
+
+        let mut level = mem::take(&mut self.written);
+        while level.len() > 1 {
+            let mut inner = Level::new(
+                INNER_HEADER_SIZE,
+                self.store.block_size(),
+                self.fill_factor,
+                true,
+            );
+
+            for (key, ident) in level {
+                let size = inner_cell_size(key.encoded_size());
+                if let Some(children) = inner.push((key, ident), size) {
+                    self.write_inner(children)?;
+                }
+            }
+            for children in inner.finish() {
+                self.write_inner(children)?;
+            }
+
+            level = mem::take(&mut self.written);
+        }
+
+        Ok(level.pop().map_or(last, |(_, root)| root))
+    }
+
+    fn write_leaf(&mut self, entries: Vec<(K, T)>) -> Result<(), TreeError> {
+        let ident = self.store.allocate_block()?;
+        let prev = match self.last_leaf.take() {
+            Some((prev, mut leaf)) => {
+                leaf.next = ident;
+                self.stage(prev, Node::Leaf(leaf))?;
+                prev
+            }
+            None => 0,
+        };
+
+        if let Some((first, _)) = entries.first() {
+            self.written.push((first.clone(), ident));
+        }
+        let mut leaf = LeafNode {
+            keys: vec![],
+            values: vec![],
+            next: 0,
+            prev,
+        };
+        for (key, value) in entries {
+            // the size of the entry was already checked when it was pushed
+            let value = LeafValue::store(&key, value, self.store)?.ok_or(TreeError::KeyTooLarge)?;
+            leaf.keys.push(key);
+            leaf.values.push(value);
+        }
+        self.last_leaf = Some((ident, leaf));
+
+        Ok(())
+    }
+
+    /// writes an inner node above the children, the smallest key of every child but the first
+    /// becomes a separator
+    fn write_inner(&mut self, children: Vec<(K, NodeIdent)>) -> Result<(), TreeError> {
+        let ident = self.store.allocate_block()?;
+        let first = children[0].0.clone();
+
+        let (mut separators, children): (Vec<K>, Vec<NodeIdent>) = children.into_iter().unzip();
+        separators.remove(0);
+        let inner = InnerNode {
+            separators,
+            children,
+        };
+        self.stage(ident, Node::Inner(inner))?;
+        self.written.push((first, ident));
+
+        Ok(())
+    }
+
+    /// stages the node for the next commit, committing the staged blocks once there are enough of
+    /// them. Nothing refers to them until the metadata is committed, so these commits don't change
+    /// the tree stored in the file.
+    fn stage(&mut self, ident: NodeIdent, node: Node<K, T>) -> Result<(), TreeError> {
+        let block = node.to_bytes(self.store.block_size());
+        self.store.set_block(ident as usize, block)?;
+
+        if self.store.node_ctr() - self.committed >= COMMIT_BLOCKS {
+            self.store.commit()?;
+            self.committed = self.store.node_ctr();
+        }
+
+        Ok(())
+    }
+}
+
+/// stages a tree holding the entries, which have to be sorted by key without duplicates, in the
+/// empty store through `FileStore::set_block` and returns its root. Nodes are filled up to
+/// `fill_factor` of their block. The blocks only become part of the tree once metadata pointing
+/// at the root is committed.
+pub fn bulk_load<K, T>(
+    store: &mut FileStore<K, T>,
+    entries: impl IntoIterator<Item = (K, T)>,
+    fill_factor: f64,
+) -> Result<NodeIdent, TreeError>
+where
+    K: SearchKey,
+    T: Value,
+{
+    let leaves = Level::new(LEAF_HEADER_SIZE, store.block_size(), fill_factor, false);
+    let mut loader = Loader {
+        store,
+        fill_factor,
+        leaves,
+        last_key: None,
+        last_leaf: None,
+        committed: 0,
+        written: vec![],
+    };
+
+    for (key, value) in entries {
+        loader.push(key, value)?;
+    }
+
+    loader.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::types::{
+        cache::CacheConfig,
+        file_store::FileStore,
+        node_store::NodeStore,
+        test_util::{assert_matches_model, check_against_model, Model, TempFile},
+        tree::{Tree, TreeError},
+    };
+
+    fn value(key: u32) -> Vec<u8> {
+        // every tenth value is moved to overflow blocks
+        let len = if key.is_multiple_of(10) {
+            300
+        } else {
+            key as usize % 20
+        };
+        vec![key as u8; len]
+    }
+
+    #[test]
+    fn bulk_loaded_leaves_are_in_block_order() {
+        let file = TempFile::new("bulk-load");
+        let entries = (0..3000).map(|key| (key, value(key)));
+        let tree =
+            Tree::<u32, Vec<u8>>::bulk_load(file.path(), 128, entries, 0.8, CacheConfig::default())
+                .unwrap();
+
+        let report = tree.verify().unwrap();
+        assert!(report.is_ok(), "{report}");
+        let expected: Vec<(u32, Vec<u8>)> = (0..3000).map(|key| (key, value(key))).collect();
+        let loaded: Vec<(u32, Vec<u8>)> = tree.iter().map(Result::unwrap).collect();
+        assert_eq!(loaded, expected);
+
+        drop(tree);
+        let tree = Tree::<u32, Vec<u8>>::load(file.path(), CacheConfig::default()).unwrap();
+        let report = tree.verify().unwrap();
+        assert!(report.is_ok(), "{report}");
+        let reloaded: Vec<(u32, Vec<u8>)> = tree.iter().map(Result::unwrap).collect();
+        assert_eq!(reloaded, expected);
+        drop(tree);
+
+        // a scan reads the leaves from the front of the file to its back
+        let (mut store, root) =
+            FileStore::<u32, Vec<u8>>::load(file.path(), CacheConfig::default()).unwrap();
+        let mut leaf = root;
+        while let Ok(inner) = store.read_inner(leaf) {
+            leaf = inner.children[0];
+        }
+        let mut leaves = vec![];
+        while leaf != 0 {
+            leaves.push(leaf);
+            leaf = store.read_leaf(leaf).unwrap().next;
+        }
+        assert!(leaves.len() > 100);
+        assert!(
+            leaves.windows(2).all(|pair| pair[0] < pair[1]),
+            "{leaves:?}"
+        );
+    }
+
+    #[test]
+    fn bulk_loaded_trees_are_valid() {
+        // the last nodes of each level are rebalanced, whatever they end up holding
+        for fill_factor in [0.5, 0.8, 1.0] {
+            for count in [0, 1, 7, 500, 3001] {
+                let file = TempFile::new("bulk-load-valid");
+                let entries = (0..count).map(|key| (key, value(key)));
+                let tree = Tree::<u32, Vec<u8>>::bulk_load(
+                    file.path(),
+                    128,
+                    entries,
+                    fill_factor,
+                    CacheConfig::default(),
+                )
+                .unwrap();
+
+                let report = tree.verify().unwrap();
+                assert!(report.is_ok(), "{fill_factor} {count}: {report}");
+                assert_eq!(report.entries, count as usize);
+            }
+        }
+    }
+
+    #[test]
+    fn full_bulk_loaded_trees_can_be_changed() {
+        // full nodes split on the first insertion into them, and their neighbours are too full to
+        // merge with once a node underflows
+        let file = TempFile::new("bulk-load-change");
+        let mut model: Model = (0..3000).map(|key| (key, value(key))).collect();
+        let mut tree =
+            Tree::bulk_load(file.path(), 128, model.clone(), 1.0, CacheConfig::default()).unwrap();
+        check_against_model(&mut tree, &mut model, &mut random::default(3), 2000);
+
+        drop(tree);
+        let tree = Tree::<u32, Vec<u8>>::load(file.path(), CacheConfig::default()).unwrap();
+        assert_matches_model(&tree, &model);
+    }
+
+    #[test]
+    fn bulk_loaded_separators_of_any_size_are_balanced() {
+        // the separators of the last two inner nodes grow towards the end of the level
+        let entries: Vec<(String, Vec<u8>)> = (0..3000)
+            .map(|key| {
+                (
+                    format!("{key:04}{}", "-".repeat(key % 37)),
+                    vec![key as u8; 20],
+                )
+            })
+            .collect();
+        for fill_factor in [0.5, 0.8, 1.0] {
+            let file = TempFile::new("bulk-load-strings");
+            let tree = Tree::bulk_load(
+                file.path(),
+                256,
+                entries.clone(),
+                fill_factor,
+                CacheConfig::default(),
+            )
+            .unwrap();
+
+            let report = tree.verify().unwrap();
+            assert!(report.is_ok(), "{fill_factor}: {report}");
+            assert_eq!(report.entries, entries.len());
+        }
+    }
+
+    #[test]
+    fn bulk_load_rejects_unsorted_entries() {
+        let file = TempFile::new("bulk-load-unsorted");
+        let load = |entries: Vec<(u32, u32)>| {
+            Tree::bulk_load(file.path(), 128, entries, 1.0, CacheConfig::default()).err()
+        };
+
+        assert!(matches!(
+            load(vec![(1, 0), (3, 0), (2, 0)]),
+            Some(TreeError::Unsorted)
+        ));
+        assert!(matches!(
+            load(vec![(1, 0), (2, 0), (2, 0)]),
+            Some(TreeError::DuplicateKey)
+        ));
+        assert!(matches!(
+            Tree::<u32, u32>::bulk_load(file.path(), 128, vec![], 0.4, CacheConfig::default()),
+            Err(TreeError::InvalidFillFactor(_))
+        ));
+    }
+}
@@ -0,0 +1,363 @@
+use std::{
+    cell::{Cell, Ref, RefCell, RefMut},
+    collections::HashMap,
+    rc::Rc,
+};
+
+use super::{
+    cache_policy::{CachePolicy, Replacement},
+    node::{InnerNode, LeafNode, Node, NodeIdent, SearchKey, Value},
+    node_store::NodeStoreError,
+};
+
+/// how many nodes a cache may hold
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheCapacity {
+    Nodes(usize),
+    /// as many nodes as fit into the given amount of bytes, counting a block for each of them
+    Bytes(usize),
+}
+
+impl CacheCapacity {
+    /// the amount of nodes of a store with blocks of `block_size` bytes, at least one
+    pub fn nodes(self, block_size: usize) -> usize {
+        match self {
+            CacheCapacity::Nodes(nodes) => nodes,
+            CacheCapacity::Bytes(bytes) => bytes / block_size,
+        }
+        .max(1)
+    }
+}
+
+impl Default for CacheCapacity {
+    fn default() -> Self {
+        CacheCapacity::Nodes(64)
+    }
+}
+
+/// how the cache of a `FileStore` is set up. Unlike the block size and durability it is not
+/// stored in the tree file, so it can be chosen every time the tree is loaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CacheConfig {
+    pub replacement: Replacement,
+    pub capacity: CacheCapacity,
+}
+
+pub struct CacheItem<K, T>
+where
+    K: SearchKey,
+    T: Value,
+{
+    node: RefCell<Node<K, T>>,
+    // amount of guards keeping the node in the cache, pinned nodes are never paged out
+    pins: Cell<usize>,
+    // whether the node was handed out for modification since it was last written back
+    dirty: Cell<bool>,
+}
+
+impl<K, T> CacheItem<K, T>
+where
+    K: SearchKey,
+    T: Value,
+{
+    fn new(node: Node<K, T>, dirty: bool) -> Rc<Self> {
+        Rc::new(CacheItem {
+            node: RefCell::new(node),
+            pins: Cell::new(0),
+            dirty: Cell::new(dirty),
+        })
+    }
+
+    fn node_mut(&self) -> RefMut<'_, Node<K, T>> {
+        self.dirty.set(true);
+        self.node.borrow_mut()
+    }
+}
+
+/// Keeps a node in the cache for as long as it is alive. A guard doesn't borrow the store it came
+/// from, so a caller can hold several nodes at once, e.g. a parent and the children it merges,
+/// and still use the store in the meantime.
+pub struct PageGuard<K, T>
+where
+    K: SearchKey,
+    T: Value,
+{
+    ident: NodeIdent,
+    item: Rc<CacheItem<K, T>>,
+}
+
+impl<K, T> PageGuard<K, T>
+where
+    K: SearchKey,
+    T: Value,
+{
+    fn new(ident: NodeIdent, item: Rc<CacheItem<K, T>>) -> Self {
+        item.pins.set(item.pins.get() + 1);
+        PageGuard { ident, item }
+    }
+
+    pub fn ident(&self) -> NodeIdent {
+        self.ident
+    }
+
+    pub fn node(&self) -> Ref<'_, Node<K, T>> {
+        self.item.node.borrow()
+    }
+
+    /// borrows the node to change it, so it is written back once it leaves the cache
+    pub fn node_mut(&self) -> RefMut<'_, Node<K, T>> {
+        self.item.node_mut()
+    }
+
+    /// like `node`, but fails if the node is not a leaf
+    pub fn leaf(&self) -> Result<Ref<'_, LeafNode<K, T>>, NodeStoreError> {
+        Ref::filter_map(self.node(), |node| match node {
+            Node::Leaf(leaf) => Some(leaf),
+            Node::Inner(_) => None,
+        })
+        .map_err(|_| NodeStoreError::InvalidReference)
+    }
+
+    /// like `node`, but fails if the node is not an inner node
+    pub fn inner(&self) -> Result<Ref<'_, InnerNode<K>>, NodeStoreError> {
+        Ref::filter_map(self.node(), |node| match node {
+            Node::Inner(inner) => Some(inner),
+            Node::Leaf(_) => None,
+        })
+        .map_err(|_| NodeStoreError::InvalidReference)
+    }
+
+    /// like `node_mut`, but fails if the node is not a leaf
+    pub fn leaf_mut(&self) -> Result<RefMut<'_, LeafNode<K, T>>, NodeStoreError> {
+        as_leaf(self.node_mut())
+    }
+
+    /// like `node_mut`, but fails if the node is not an inner node
+    pub fn inner_mut(&self) -> Result<RefMut<'_, InnerNode<K>>, NodeStoreError> {
+        as_inner(self.node_mut())
+    }
+}
+
+impl<K, T> Drop for PageGuard<K, T>
+where
+    K: SearchKey,
+    T: Value,
+{
+    fn drop(&mut self) {
+        self.item.pins.set(self.item.pins.get() - 1);
+    }
+}
+
+/// narrows the borrowed node down to a leaf, fails if it is an inner node
+pub fn as_leaf<K, T>(
+    node: RefMut<'_, Node<K, T>>,
+) -> Result<RefMut<'_, LeafNode<K, T>>, NodeStoreError>
+where
+    K: SearchKey,
+    T: Value,
+{
+    RefMut::filter_map(node, |node| match node {
+        Node::Leaf(leaf) => Some(leaf),
+        Node::Inner(_) => None,
+    })
+    .map_err(|_| NodeStoreError::InvalidReference)
+}
+
+/// narrows the borrowed node down to an inner node, fails if it is a leaf
+pub fn as_inner<K, T>(
+    node: RefMut<'_, Node<K, T>>,
+) -> Result<RefMut<'_, InnerNode<K>>, NodeStoreError>
+where
+    K: SearchKey,
+    T: Value,
+{
+    RefMut::filter_map(node, |node| match node {
+        Node::Inner(inner) => Some(inner),
+        Node::Leaf(_) => None,
+    })
+    .map_err(|_| NodeStoreError::InvalidReference)
+}
+
+pub struct Cache<K, T>
+where
+    K: SearchKey,
+    T: Value,
+{
+    nodes: HashMap<NodeIdent, Rc<CacheItem<K, T>>>,
+    // decides which node to page out once the cache is full
+    policy: Box<dyn CachePolicy>,
+    capacity: usize,
+}
+
+impl<K, T> Cache<K, T>
+where
+    K: SearchKey,
+    T: Value,
+{
+    pub fn has_node(&mut self, node: NodeIdent) -> bool {
+        self.nodes.contains_key(&node)
+    }
+    /// gets an already present node from the cache to read it and tells the policy it was used
+    pub fn get_node(&mut self, node: NodeIdent) -> Option<Ref<'_, Node<K, T>>> {
+        let item = self.nodes.get(&node)?;
+        self.policy.access(node);
+        Some(item.node.borrow())
+    }
+
+    /// like `get_node`, but marks the node as dirty since it may be changed
+    pub fn get_node_mut(&mut self, node: NodeIdent) -> Option<RefMut<'_, Node<K, T>>> {
+        let item = self.nodes.get(&node)?;
+        self.policy.access(node);
+        Some(item.node_mut())
+    }
+
+    /// pins an already present node, it stays in the cache until the guard is dropped
+    pub fn pin(&mut self, node: NodeIdent) -> Option<PageGuard<K, T>> {
+        let item = self.nodes.get(&node)?;
+        self.policy.access(node);
+        Some(PageGuard::new(node, item.clone()))
+    }
+
+    /// takes in a node that was just read from its block and caches it. Returns the dirty nodes
+    /// displaced by the operation. These nodes can be considered unused and should be moved to
+    /// long-term storage, displaced clean nodes are simply dropped.
+    pub fn cache_node(
+        &mut self,
+        ident: NodeIdent,
+        node: Node<K, T>,
+    ) -> Vec<(NodeIdent, Node<K, T>)> {
+        if let Some(item) = self.nodes.get(&ident) {
+            *item.node.borrow_mut() = node;
+            item.dirty.set(false);
+            self.policy.access(ident);
+            return vec![];
+        }
+
+        self.nodes.insert(ident, CacheItem::new(node, false));
+        self.policy.insert(ident);
+
+        // never page out the node that was just cached
+        self.page_out(Some(ident))
+    }
+
+    /// pages out nodes until the cache is back within its capacity, as far as that is possible
+    /// without touching pinned nodes or `keep`. Returns the dirty ones.
+    fn page_out(&mut self, keep: Option<NodeIdent>) -> Vec<(NodeIdent, Node<K, T>)> {
+        let mut evicted = vec![];
+
+        while self.nodes.len() > self.capacity {
+            let nodes = &self.nodes;
+            let mut evictable = |ident| {
+                Some(ident) != keep && nodes.get(&ident).is_some_and(|item| item.pins.get() == 0)
+            };
+            let Some(ident) = self.policy.evict(&mut evictable) else {
+                break;
+            };
+
+            // unpinned nodes are not shared with any guard
+            let item = self.nodes.remove(&ident).and_then(Rc::into_inner);
+            match item {
+                Some(item) if item.dirty.get() => evicted.push((ident, item.node.into_inner())),
+                _ => {}
+            }
+        }
+
+        evicted
+    }
+
+    /// puts back a dirty node that was handed out for storage but could not be stored, even if
+    /// the cache is full
+    pub fn restore(&mut self, ident: NodeIdent, node: Node<K, T>) {
+        if self
+            .nodes
+            .insert(ident, CacheItem::new(node, true))
+            .is_none()
+        {
+            self.policy.insert(ident);
+        }
+    }
+
+    /// whether any cached node was changed since it was last written back
+    pub fn is_dirty(&self) -> bool {
+        self.nodes.values().any(|item| item.dirty.get())
+    }
+
+    /// marks every cached node as clean once their current state was written back
+    pub fn mark_clean(&mut self) {
+        self.nodes.values().for_each(|item| item.dirty.set(false));
+    }
+
+    /// changes the amount of nodes the cache holds. Returns the dirty nodes paged out to get down
+    /// to the new capacity, they should be moved to long-term storage. Pinned nodes stay cached
+    /// even if that leaves the cache above its capacity.
+    pub fn resize(&mut self, capacity: usize) -> Vec<(NodeIdent, Node<K, T>)> {
+        self.capacity = capacity;
+        self.policy.set_capacity(capacity);
+
+        self.page_out(None)
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// removes a node from the cache without handing it out for storage
+    pub fn remove(&mut self, ident: NodeIdent) {
+        if self.nodes.remove(&ident).is_some() {
+            self.policy.remove(ident);
+        }
+    }
+
+    /// the current state of every dirty node
+    pub fn dirty_nodes(&self) -> impl Iterator<Item = (NodeIdent, Ref<'_, Node<K, T>>)> {
+        self.nodes
+            .iter()
+            .filter(|(_, item)| item.dirty.get())
+            .map(|(ident, item)| (*ident, item.node.borrow()))
+    }
+
+    /// removes every node that isn't pinned and returns the dirty ones
+    pub fn drain(&mut self) -> Vec<(NodeIdent, Node<K, T>)> {
+        let idents: Vec<NodeIdent> = self
+            .nodes
+            .iter()
+            .filter(|(_, item)| item.pins.get() == 0)
+            .map(|(ident, _)| *ident)
+            .collect();
+
+        let mut drained = vec![];
+        for ident in idents {
+            self.policy.remove(ident);
+            let item = self.nodes.remove(&ident).and_then(Rc::into_inner);
+            match item {
+                Some(item) if item.dirty.get() => drained.push((ident, item.node.into_inner())),
+                _ => {}
+            }
+        }
+
+        drained
+    }
+
+    /// drops all cached nodes without handing them out for storage
+    pub fn clear(&mut self) {
+        self.policy.clear();
+        self.nodes.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// creates a cache holding up to `capacity` nodes
+    pub fn new(replacement: Replacement, capacity: usize) -> Self {
+        Self {
+            nodes: HashMap::new(),
+            policy: replacement.build(capacity),
+            capacity,
+        }
+    }
+}
@@ -0,0 +1,518 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use super::node::NodeIdent;
+
+/// Decides which node leaves the cache once it is full. The policy only sees the identifiers of
+/// the cached nodes, the nodes themselves are kept by the `Cache`.
+pub trait CachePolicy {
+    /// a cached node was used again
+    fn access(&mut self, ident: NodeIdent);
+    /// a node that was not cached was added to the cache
+    fn insert(&mut self, ident: NodeIdent);
+    /// a node left the cache without being picked by `evict`, e.g. because it was freed
+    fn remove(&mut self, ident: NodeIdent);
+    /// picks a cached node for which `evictable` holds, forgets it and returns it. Returns `None`
+    /// if there is no such node.
+    fn evict(&mut self, evictable: &mut dyn FnMut(NodeIdent) -> bool) -> Option<NodeIdent>;
+    /// forgets all cached nodes
+    fn clear(&mut self);
+    /// the cache now holds up to `capacity` nodes. Policies sizing their lists by it adjust them,
+    /// evicting the nodes above the new capacity is left to the `Cache`.
+    fn set_capacity(&mut self, _capacity: usize) {}
+}
+
+/// which `CachePolicy` a `FileStore` uses for its cache
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Replacement {
+    /// evicts the node that was used least recently
+    Lru,
+    /// sweeps over the nodes in the order they were cached, evicting the first one that was not
+    /// used since the last sweep
+    #[default]
+    Clock,
+    /// keeps nodes used only once in a small FIFO queue, so a scan doesn't push out the nodes
+    /// used again and again
+    TwoQueue,
+    /// adaptive replacement cache, balances between recently and frequently used nodes
+    Arc,
+}
+
+impl Replacement {
+    /// creates the policy for a cache holding up to `capacity` nodes
+    pub fn build(self, capacity: usize) -> Box<dyn CachePolicy> {
+        match self {
+            Replacement::Lru => Box::new(Lru::default()),
+            Replacement::Clock => Box::new(Clock::default()),
+            Replacement::TwoQueue => Box::new(TwoQueue::new(capacity)),
+            Replacement::Arc => Box::new(AdaptiveReplacement::new(capacity)),
+        }
+    }
+}
+
+/// nodes ordered by their last use, from least to most recently used
+#[derive(Default)]
+struct LruList {
+    order: BTreeMap<u64, NodeIdent>,
+    ticks: HashMap<NodeIdent, u64>,
+    tick: u64,
+}
+
+impl LruList {
+    fn len(&self) -> usize {
+        self.ticks.len()
+    }
+
+    fn contains(&self, ident: NodeIdent) -> bool {
+        self.ticks.contains_key(&ident)
+    }
+
+    /// adds the node as the most recently used one, moving it there if it is already present
+    fn push(&mut self, ident: NodeIdent) {
+        self.remove(ident);
+        self.tick += 1;
+        self.order.insert(self.tick, ident);
+        self.ticks.insert(ident, self.tick);
+    }
+
+    /// returns whether the node was present
+    fn remove(&mut self, ident: NodeIdent) -> bool {
+        match self.ticks.remove(&ident) {
+            Some(tick) => {
+                self.order.remove(&tick);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn pop_lru(&mut self) -> Option<NodeIdent> {
+        let (_, ident) = self.order.pop_first()?;
+        self.ticks.remove(&ident);
+        Some(ident)
+    }
+
+    /// removes the least recently used node for which `evictable` holds
+    fn evict(&mut self, evictable: &mut dyn FnMut(NodeIdent) -> bool) -> Option<NodeIdent> {
+        let ident = self
+            .order
+            .values()
+            .copied()
+            .find(|&ident| evictable(ident))?;
+        self.remove(ident);
+        Some(ident)
+    }
+
+    fn clear(&mut self) {
+        self.order.clear();
+        self.ticks.clear();
+    }
+}
+
+#[derive(Default)]
+pub struct Lru {
+    nodes: LruList,
+}
+
+impl CachePolicy for Lru {
+    fn access(&mut self, ident: NodeIdent) {
+        self.nodes.push(ident);
+    }
+
+    fn insert(&mut self, ident: NodeIdent) {
+        self.nodes.push(ident);
+    }
+
+    fn remove(&mut self, ident: NodeIdent) {
+        self.nodes.remove(ident);
+    }
+
+    fn evict(&mut self, evictable: &mut dyn FnMut(NodeIdent) -> bool) -> Option<NodeIdent> {
+        self.nodes.evict(evictable)
+    }
+
+    fn clear(&mut self) {
+        self.nodes.clear();
+    }
+}
+
+#[derive(Default)]
+pub struct Clock {
+    // the nodes in the order the hand passes them, the hand points at the front
+    ring: VecDeque<NodeIdent>,
+    // whether each node was used since the hand last passed it
+    referenced: HashMap<NodeIdent, bool>,
+}
+
+impl CachePolicy for Clock {
+    fn access(&mut self, ident: NodeIdent) {
+        if let Some(referenced) = self.referenced.get_mut(&ident) {
+            *referenced = true;
+        }
+    }
+
+    fn insert(&mut self, ident: NodeIdent) {
+        // right behind the hand, so it is passed last
+        self.ring.push_back(ident);
+        self.referenced.insert(ident, false);
+    }
+
+    fn remove(&mut self, ident: NodeIdent) {
+        if self.referenced.remove(&ident).is_some() {
+            self.ring.retain(|&i| i != ident);
+        }
+    }
+
+    fn evict(&mut self, evictable: &mut dyn FnMut(NodeIdent) -> bool) -> Option<NodeIdent> {
+        // the first pass clears every reference bit, so the second one finds a node if there is
+        // any evictable one
+        for _ in 0..2 * self.ring.len() {
+            let ident = self.ring.pop_front()?;
+            let referenced = self.referenced.get_mut(&ident)?;
+
+            if !*referenced && evictable(ident) {
+                self.referenced.remove(&ident);
+                return Some(ident);
+            }
+            *referenced = false;
+            self.ring.push_back(ident);
+        }
+
+        None
+    }
+
+    fn clear(&mut self) {
+        self.ring.clear();
+        self.referenced.clear();
+    }
+}
+
+/// the full version of 2Q by Johnson and Shasha
+pub struct TwoQueue {
+    // nodes used once, in the order they were cached
+    recent: LruList,
+    // nodes evicted from `recent` recently, they are no longer cached
+    ghosts: LruList,
+    // nodes used again after they were evicted from `recent`
+    frequent: LruList,
+    max_recent: usize,
+    max_ghosts: usize,
+}
+
+impl TwoQueue {
+    pub fn new(capacity: usize) -> Self {
+        let mut policy = TwoQueue {
+            recent: LruList::default(),
+            ghosts: LruList::default(),
+            frequent: LruList::default(),
+            max_recent: 0,
+            max_ghosts: 0,
+        };
+        policy.set_capacity(capacity);
+        policy
+    }
+}
+
+impl CachePolicy for TwoQueue {
+    fn access(&mut self, ident: NodeIdent) {
+        // a second use of a node in `recent` is likely correlated with the first, e.g. both
+        // happened during the same descent, so it doesn't count
+        if self.frequent.contains(ident) {
+            self.frequent.push(ident);
+        }
+    }
+
+    fn insert(&mut self, ident: NodeIdent) {
+        if self.ghosts.remove(ident) {
+            self.frequent.push(ident);
+        } else {
+            self.recent.push(ident);
+        }
+    }
+
+    fn remove(&mut self, ident: NodeIdent) {
+        if !self.recent.remove(ident) {
+            self.frequent.remove(ident);
+        }
+    }
+
+    fn evict(&mut self, evictable: &mut dyn FnMut(NodeIdent) -> bool) -> Option<NodeIdent> {
+        if self.recent.len() > self.max_recent {
+            if let Some(ident) = self.recent.evict(evictable) {
+                self.ghosts.push(ident);
+                if self.ghosts.len() > self.max_ghosts {
+                    self.ghosts.pop_lru();
+                }
+                return Some(ident);
+            }
+        }
+
+        // nodes evicted from `recent` only become ghosts if it is too long
+        self.frequent
+            .evict(evictable)
+            .or_else(|| self.recent.evict(evictable))
+    }
+
+    fn clear(&mut self) {
+        self.recent.clear();
+        self.ghosts.clear();
+        self.frequent.clear();
+    }
+
+    fn set_capacity(&mut self, capacity: usize) {
+        self.max_recent = (capacity / 4).max(1);
+        self.max_ghosts = (capacity / 2).max(1);
+        while self.ghosts.len() > self.max_ghosts && self.ghosts.pop_lru().is_some() {}
+    }
+}
+
+/// the adaptive replacement cache by Megiddo and Modha
+pub struct AdaptiveReplacement {
+    // cached nodes used once since they were cached
+    recent: LruList,
+    // cached nodes used at least twice
+    frequent: LruList,
+    // nodes recently evicted from `recent` and `frequent`, they are no longer cached
+    recent_ghosts: LruList,
+    frequent_ghosts: LruList,
+    // the amount of nodes `recent` aims for, grows on hits in `recent_ghosts` and shrinks on hits
+    // in `frequent_ghosts`
+    target: usize,
+    // whether the node cached last was found in `frequent_ghosts`
+    frequent_ghost_hit: bool,
+    capacity: usize,
+}
+
+impl AdaptiveReplacement {
+    pub fn new(capacity: usize) -> Self {
+        AdaptiveReplacement {
+            recent: LruList::default(),
+            frequent: LruList::default(),
+            recent_ghosts: LruList::default(),
+            frequent_ghosts: LruList::default(),
+            target: 0,
+            frequent_ghost_hit: false,
+            capacity: capacity.max(1),
+        }
+    }
+
+    /// keeps the ghosts from growing past the size of the cache
+    fn trim_ghosts(&mut self) {
+        while self.recent.len() + self.recent_ghosts.len() > self.capacity
+            && self.recent_ghosts.pop_lru().is_some()
+        {}
+        while self.recent.len()
+            + self.frequent.len()
+            + self.recent_ghosts.len()
+            + self.frequent_ghosts.len()
+            > 2 * self.capacity
+            && self.frequent_ghosts.pop_lru().is_some()
+        {}
+    }
+}
+
+impl CachePolicy for AdaptiveReplacement {
+    fn access(&mut self, ident: NodeIdent) {
+        if self.recent.remove(ident) || self.frequent.contains(ident) {
+            self.frequent.push(ident);
+        }
+    }
+
+    fn insert(&mut self, ident: NodeIdent) {
+        let recent_ghosts = self.recent_ghosts.len().max(1);
+        let frequent_ghosts = self.frequent_ghosts.len().max(1);
+        self.frequent_ghost_hit = false;
+
+        if self.recent_ghosts.remove(ident) {
+            // `recent` was too small to keep the node
+            self.target =
+                (self.target + (frequent_ghosts / recent_ghosts).max(1)).min(self.capacity);
+            self.frequent.push(ident);
+        } else if self.frequent_ghosts.remove(ident) {
+            self.target = self
+                .target
+                .saturating_sub((recent_ghosts / frequent_ghosts).max(1));
+            self.frequent_ghost_hit = true;
+            self.frequent.push(ident);
+        } else {
+            self.recent.push(ident);
+        }
+
+        self.trim_ghosts();
+    }
+
+    fn remove(&mut self, ident: NodeIdent) {
+        if !self.recent.remove(ident) {
+            self.frequent.remove(ident);
+        }
+    }
+
+    fn evict(&mut self, evictable: &mut dyn FnMut(NodeIdent) -> bool) -> Option<NodeIdent> {
+        let from_recent = if self.frequent_ghost_hit {
+            self.recent.len() >= self.target.max(1)
+        } else {
+            self.recent.len() > self.target
+        };
+
+        let ident = if from_recent {
+            self.recent
+                .evict(evictable)
+                .map(|ident| (ident, true))
+                .or_else(|| self.frequent.evict(evictable).map(|ident| (ident, false)))
+        } else {
+            self.frequent
+                .evict(evictable)
+                .map(|ident| (ident, false))
+                .or_else(|| self.recent.evict(evictable).map(|ident| (ident, true)))
+        };
+
+        let (ident, recent) = ident?;
+        if recent {
+            self.recent_ghosts.push(ident);
+        } else {
+            self.frequent_ghosts.push(ident);
+        }
+        self.trim_ghosts();
+
+        Some(ident)
+    }
+
+    fn clear(&mut self) {
+        self.recent.clear();
+        self.frequent.clear();
+        self.recent_ghosts.clear();
+        self.frequent_ghosts.clear();
+        self.target = 0;
+        self.frequent_ghost_hit = false;
+    }
+
+    fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity.max(1);
+        self.target = self.target.min(self.capacity);
+        self.trim_ghosts();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use random::Source;
+
+    use super::*;
+
+    const POLICIES: [Replacement; 4] = [
+        Replacement::Lru,
+        Replacement::Clock,
+        Replacement::TwoQueue,
+        Replacement::Arc,
+    ];
+
+    fn evict_all(policy: &mut dyn CachePolicy) -> Vec<NodeIdent> {
+        std::iter::from_fn(|| policy.evict(&mut |_| true)).collect()
+    }
+
+    #[test]
+    fn lru_evicts_the_least_recently_used_node() {
+        let mut policy = Lru::default();
+        for ident in 1..=4 {
+            policy.insert(ident);
+        }
+        policy.access(2);
+        policy.access(1);
+
+        assert_eq!(evict_all(&mut policy), [3, 4, 2, 1]);
+    }
+
+    #[test]
+    fn clock_gives_used_nodes_a_second_pass() {
+        let mut policy = Clock::default();
+        for ident in 1..=4 {
+            policy.insert(ident);
+        }
+        policy.access(1);
+        policy.access(3);
+
+        // the hand clears the bits of 1 and 3 while passing them
+        assert_eq!(policy.evict(&mut |_| true), Some(2));
+        assert_eq!(policy.evict(&mut |_| true), Some(4));
+        assert_eq!(evict_all(&mut policy), [1, 3]);
+    }
+
+    #[test]
+    fn two_queue_keeps_frequent_nodes_during_a_scan() {
+        let mut policy = TwoQueue::new(8);
+        for ident in 1..=3 {
+            policy.insert(ident);
+        }
+        // 1 leaves `recent` as a ghost, so caching it again moves it to `frequent`
+        assert_eq!(policy.evict(&mut |_| true), Some(1));
+        policy.insert(1);
+        assert!(policy.frequent.contains(1));
+
+        // nodes used only once by the scan push each other out instead
+        for ident in 100..200 {
+            policy.insert(ident);
+            assert_ne!(policy.evict(&mut |_| true), Some(1));
+        }
+        assert!(policy.frequent.contains(1));
+    }
+
+    #[test]
+    fn arc_adapts_its_target_on_ghost_hits() {
+        let mut policy = AdaptiveReplacement::new(4);
+        for ident in 1..=4 {
+            policy.insert(ident);
+        }
+        assert_eq!(policy.target, 0);
+
+        // a hit in the ghosts of `recent` makes room for more recent nodes
+        assert_eq!(policy.evict(&mut |_| true), Some(1));
+        policy.insert(1);
+        assert_eq!(policy.target, 1);
+        assert!(policy.frequent.contains(1));
+
+        // a hit in the ghosts of `frequent` makes room for more frequent nodes
+        policy.access(3);
+        policy.access(4);
+        assert_eq!(policy.evict(&mut |_| true), Some(1));
+        assert!(policy.frequent_ghosts.contains(1));
+        policy.insert(1);
+        assert_eq!(policy.target, 0);
+        assert!(policy.frequent.contains(1));
+    }
+
+    #[test]
+    fn policies_only_evict_cached_evictable_nodes() {
+        for replacement in POLICIES {
+            let mut source = random::default(7);
+            let mut policy = replacement.build(8);
+            let mut cached = BTreeSet::new();
+
+            for _ in 0..5000 {
+                let ident = (source.read_u64() % 32) as NodeIdent;
+                match source.read_u64() % 4 {
+                    0 if cached.contains(&ident) => policy.access(ident),
+                    0 | 1 if !cached.contains(&ident) => {
+                        policy.insert(ident);
+                        cached.insert(ident);
+                    }
+                    2 if cached.remove(&ident) => policy.remove(ident),
+                    _ => {
+                        // odd nodes stand in for pinned ones
+                        let evicted = policy.evict(&mut |ident| ident % 2 == 0);
+                        match evicted {
+                            Some(ident) => {
+                                assert!(cached.remove(&ident), "{replacement:?} evicted {ident}");
+                                assert_eq!(ident % 2, 0, "{replacement:?} evicted {ident}");
+                            }
+                            None => assert!(
+                                cached.iter().all(|ident| ident % 2 == 1),
+                                "{replacement:?} found nothing to evict in {cached:?}"
+                            ),
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,134 @@
+use std::mem;
+
+use super::{
+    node::{SearchKey, Value},
+    tree::{Tree, TreeError},
+};
+
+/// A single entry of a tree, which may or may not be present. Values are kept on disk, so every
+/// change made through an entry is written to the tree right away.
+pub enum Entry<'a, K, T>
+where
+    K: SearchKey + 'static,
+    T: Value + 'static,
+{
+    Occupied(OccupiedEntry<'a, K, T>),
+    Vacant(VacantEntry<'a, K, T>),
+}
+
+pub struct OccupiedEntry<'a, K, T>
+where
+    K: SearchKey + 'static,
+    T: Value + 'static,
+{
+    tree: &'a mut Tree<K, T>,
+    key: K,
+    value: T,
+}
+
+pub struct VacantEntry<'a, K, T>
+where
+    K: SearchKey + 'static,
+    T: Value + 'static,
+{
+    tree: &'a mut Tree<K, T>,
+    key: K,
+}
+
+impl<'a, K, T> Entry<'a, K, T>
+where
+    K: SearchKey + 'static,
+    T: Value + 'static,
+{
+    pub fn new(tree: &'a mut Tree<K, T>, key: K) -> Result<Self, TreeError> {
+        Ok(match tree.search(&key)? {
+            Some(value) => Entry::Occupied(OccupiedEntry { tree, key, value }),
+            None => Entry::Vacant(VacantEntry { tree, key }),
+        })
+    }
+
+    pub fn key(&self) -> &K {
+        match self {
+            Entry::Occupied(entry) => entry.key(),
+            Entry::Vacant(entry) => entry.key(),
+        }
+    }
+
+    /// inserts the default if the entry is vacant. Returns the value stored for the key.
+    pub fn or_insert(self, default: T) -> Result<T, TreeError> {
+        self.or_insert_with(|| default)
+    }
+
+    pub fn or_insert_with<F>(self, default: F) -> Result<T, TreeError>
+    where
+        F: FnOnce() -> T,
+    {
+        match self {
+            Entry::Occupied(entry) => Ok(entry.value),
+            Entry::Vacant(entry) => entry.insert(default()),
+        }
+    }
+
+    /// updates the value of an occupied entry and writes it back to the tree
+    pub fn and_modify<F>(self, f: F) -> Result<Self, TreeError>
+    where
+        F: FnOnce(&mut T),
+    {
+        match self {
+            Entry::Occupied(mut entry) => {
+                let mut value = entry.value.clone();
+                f(&mut value);
+                entry.insert(value)?;
+
+                Ok(Entry::Occupied(entry))
+            }
+            Entry::Vacant(entry) => Ok(Entry::Vacant(entry)),
+        }
+    }
+}
+
+impl<K, T> OccupiedEntry<'_, K, T>
+where
+    K: SearchKey + 'static,
+    T: Value + 'static,
+{
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    pub fn get(&self) -> &T {
+        &self.value
+    }
+
+    /// replaces the stored value, returning the old one
+    pub fn insert(&mut self, value: T) -> Result<T, TreeError> {
+        self.tree.upsert(self.key.clone(), value.clone())?;
+        Ok(mem::replace(&mut self.value, value))
+    }
+
+    /// removes the entry from the tree, returning its value
+    pub fn remove(self) -> Result<T, TreeError> {
+        self.tree.remove(&self.key)?;
+        Ok(self.value)
+    }
+}
+
+impl<K, T> VacantEntry<'_, K, T>
+where
+    K: SearchKey + 'static,
+    T: Value + 'static,
+{
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    pub fn into_key(self) -> K {
+        self.key
+    }
+
+    /// inserts the value for the entry's key and returns it
+    pub fn insert(self, value: T) -> Result<T, TreeError> {
+        self.tree.insert(self.key, value.clone())?;
+        Ok(value)
+    }
+}
@@ -1,105 +1,564 @@
 use std::{
-    fmt::Debug,
-    fs::{File, OpenOptions},
-    io,
-    marker::PhantomData,
+    cell::{Ref, RefMut},
+    collections::BTreeMap,
+    error::Error,
+    fmt::{self, Debug},
+    fs::{self, File, OpenOptions},
+    io, mem,
     os::unix::fs::FileExt,
 };
 
-use crate::types::node::{NodeIdent, SearchKey};
+use crate::types::node::{NodeIdent, SearchKey, Value};
 
 use super::{
-    node::Node,
-    node_store::{ByteSerialize, NodeStore, NodeStoreError, BLOCK_SIZE},
-    second_chance_cache::{Cache, CacheItem},
+    cache::{Cache, CacheCapacity, CacheConfig, PageGuard},
+    node::{InnerNode, LeafNode, LeafValue, Node},
+    node_store::{
+        valid_block_size, Block, ByteSerialize, Encode, NodeStore, NodeStoreError, SnapshotId,
+    },
+    page_table::PageTable,
+    wal::Wal,
 };
 
-impl<T, const S: usize> ByteSerialize for Node<T, S>
+/// first byte of every block but the metadata, telling what the block holds. A reference to a
+/// block that was freed or reused for something else is noticed when reading it.
+const LEAF_TAG: u8 = 1;
+const INNER_TAG: u8 = 2;
+const FREE_TAG: u8 = 3;
+const OVERFLOW_TAG: u8 = 4;
+
+/// bytes at the start of a leaf block: the node tag, the entry count and the links to the
+/// neighbouring leaves
+pub const LEAF_HEADER_SIZE: usize = size_of::<u8>() + size_of::<u16>() + 2 * size_of::<NodeIdent>();
+/// bytes at the start of an inner node block: the node tag, the entry count and the leftmost child
+pub const INNER_HEADER_SIZE: usize = size_of::<u8>() + size_of::<u16>() + size_of::<NodeIdent>();
+/// each entry has a slot after the header holding the offset of its cell
+pub const SLOT_SIZE: usize = size_of::<u16>();
+/// bytes at the start of an overflow block: the tag and the link to the next block of the chain
+pub const OVERFLOW_HEADER_SIZE: usize = size_of::<u8>() + size_of::<NodeIdent>();
+/// bytes stored in place of a value that was moved to overflow blocks: the first block and the
+/// length of the value
+pub const OVERFLOW_REF_SIZE: usize = size_of::<NodeIdent>() + size_of::<u32>();
+/// set in the value length of a leaf cell if the value was moved to overflow blocks
+const OVERFLOW_FLAG: u16 = 0x8000;
+/// the last bytes of every block hold the checksum of the rest of it. Keeping it inside of the
+/// block keeps the blocks aligned to the pages of the file.
+pub const CHECKSUM_SIZE: usize = size_of::<u32>();
+
+/// bytes of a block available to its contents, in front of the checksum
+pub fn payload_size(block_size: usize) -> usize {
+    block_size - CHECKSUM_SIZE
+}
+
+/// first bytes of the metadata block, followed by the version of the file format. Files written
+/// with the fixed-size nodes of the first versions of the tree don't start with them.
+const MAGIC: [u8; 6] = *b"BPTREE";
+const FORMAT_VERSION: u16 = 1;
+/// bytes of the metadata block taken up by the magic and the format version
+const FORMAT_HEADER_SIZE: usize = MAGIC.len() + size_of::<u16>();
+
+/// smallest amount of bytes a disk writes at once
+const SECTOR_SIZE: usize = 512;
+
+/// bytes of the block at `place` covered by its checksum, which is stored behind them. The
+/// metadata block is rewritten in place to publish commits, so it keeps its contents and checksum
+/// within the first sector: a torn write can only leave either the old or the new metadata, no
+/// matter how large the block is.
+fn checked_size(place: usize, block_size: usize) -> usize {
+    match place {
+        0 => block_size.min(SECTOR_SIZE),
+        _ => block_size,
+    }
+}
+
+/// largest amount of bytes a single entry may take up, so at least four of them fit into a leaf
+pub fn max_cell_size(block_size: usize) -> usize {
+    (payload_size(block_size) - LEAF_HEADER_SIZE) / 4
+}
+
+/// bytes of payload in each overflow block, behind its header
+pub fn overflow_payload_size(block_size: usize) -> usize {
+    payload_size(block_size) - OVERFLOW_HEADER_SIZE
+}
+
+/// lookup table of the CRC-32 used by zlib and PNG
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < table.len() {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb88320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0, |crc, &byte| {
+        CRC_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+/// reads the block of `block_size` bytes stored at `place` in the file and makes sure it matches
+/// its checksum
+pub fn read_block_at(
+    file: &File,
+    place: usize,
+    block_size: usize,
+) -> Result<Block, NodeStoreError> {
+    let mut block = vec![0; block_size];
+    file.read_exact_at(&mut block, (block_size * place) as u64)
+        .map_err(|_| NodeStoreError::ReadFailed)?;
+
+    let checked = checked_size(place, block_size);
+    let payload = payload_size(checked);
+    let mut entry = [0; CHECKSUM_SIZE];
+    entry.copy_from_slice(&block[payload..checked]);
+    let expected = u32::from_le_bytes(entry);
+    let actual = crc32(&block[..payload]);
+
+    if expected != actual {
+        return Err(NodeStoreError::Corrupted {
+            block: place as NodeIdent,
+            expected,
+            actual,
+        });
+    }
+
+    Ok(block)
+}
+
+/// writes the block to `place` in the file, filling in its checksum
+pub fn write_block_at(file: &File, place: usize, block: &[u8]) -> Result<(), NodeStoreError> {
+    let checked = checked_size(place, block.len());
+    let payload = payload_size(checked);
+    let mut stored = block.to_vec();
+    stored[payload..checked].copy_from_slice(&crc32(&block[..payload]).to_le_bytes());
+
+    file.write_all_at(&stored, (stored.len() * place) as u64)
+        .map_err(|_| NodeStoreError::WriteFailed)
+}
+
+/// bytes a leaf entry takes up: its slot, the key and value lengths, the key and the value
+pub fn leaf_cell_size(key_size: usize, value_size: usize) -> usize {
+    SLOT_SIZE + 2 * size_of::<u16>() + key_size + value_size
+}
+
+/// bytes an inner entry takes up: its slot, the key length, its right child and the key
+pub fn inner_cell_size(key_size: usize) -> usize {
+    SLOT_SIZE + size_of::<u16>() + size_of::<NodeIdent>() + key_size
+}
+
+fn read_u16(block: &[u8], index: usize) -> u16 {
+    let mut entry = [0; size_of::<u16>()];
+    entry.copy_from_slice(&block[index..index + size_of::<u16>()]);
+    u16::from_le_bytes(entry)
+}
+
+pub fn read_ident(block: &[u8], index: usize) -> NodeIdent {
+    let mut entry = [0; size_of::<NodeIdent>()];
+    entry.copy_from_slice(&block[index..index + size_of::<NodeIdent>()]);
+    NodeIdent::from_le_bytes(entry)
+}
+
+// Nodes use a slotted layout: the header is followed by one slot per entry holding the offset of
+// the entry's cell. The cells are packed from the checksum at the end of the block towards the
+// slots.
+
+/// writes the slots and cells of a node behind its header
+fn pack_cells(bytes: &mut [u8], header_size: usize, cells: Vec<Vec<u8>>) {
+    let mut index = header_size;
+    let mut cell_end = payload_size(bytes.len());
+
+    for cell in cells {
+        let cell_start = cell_end - cell.len();
+        bytes[cell_start..cell_end].copy_from_slice(&cell);
+
+        bytes[index..index + SLOT_SIZE].copy_from_slice(&(cell_start as u16).to_le_bytes());
+        index += SLOT_SIZE;
+
+        cell_end = cell_start;
+    }
+}
+
+fn malformed(block: NodeIdent, reason: String) -> NodeStoreError {
+    NodeStoreError::Malformed { block, reason }
+}
+
+/// reads the offsets of the cells from the slots behind the header, making sure the slots and
+/// cells lie within the block
+fn cell_offsets(
+    ident: NodeIdent,
+    block: &[u8],
+    header_size: usize,
+) -> Result<Vec<usize>, NodeStoreError> {
+    let size = read_u16(block, size_of::<u8>()) as usize;
+    let slots_end = header_size + size * SLOT_SIZE;
+    if slots_end > payload_size(block.len()) {
+        return Err(malformed(
+            ident,
+            format!("{size} slots don't fit into the block"),
+        ));
+    }
+
+    let offsets: Vec<usize> = (0..size)
+        .map(|i| read_u16(block, header_size + i * SLOT_SIZE) as usize)
+        .collect();
+    match offsets.iter().position(|&offset| offset < slots_end) {
+        Some(i) => Err(malformed(ident, format!("cell {i} overlaps the slots"))),
+        None => Ok(offsets),
+    }
+}
+
+/// decodes a key or value, `None` if the bytes don't have the size every encoding of it has or
+/// are no valid encoding
+fn decode<E: Encode>(bytes: &[u8]) -> Option<E> {
+    match E::FIXED_SIZE {
+        Some(size) if size != bytes.len() => None,
+        _ => E::decode(bytes),
+    }
+}
+
+/// Reads the fields of a cell one after the other. Every read returns `None` once it would reach
+/// past the payload of the block.
+struct CellReader<'a> {
+    payload: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> CellReader<'a> {
+    fn new(block: &'a [u8], offset: usize) -> Self {
+        CellReader {
+            payload: &block[..payload_size(block.len())],
+            offset,
+        }
+    }
+
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self
+            .payload
+            .get(self.offset..self.offset.checked_add(len)?)?;
+        self.offset += len;
+        Some(bytes)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(size_of::<u16>()).map(|bytes| read_u16(bytes, 0))
+    }
+
+    fn ident(&mut self) -> Option<NodeIdent> {
+        self.bytes(size_of::<NodeIdent>())
+            .map(|bytes| read_ident(bytes, 0))
+    }
+
+    /// the key length, value length, key and value of a leaf cell
+    fn leaf_entry<K: SearchKey, T: Value>(&mut self) -> Option<(K, LeafValue<T>)> {
+        let key_size = self.u16()? as usize;
+        let value_size = self.u16()?;
+        let key = decode(self.bytes(key_size)?)?;
+
+        let value = if value_size & OVERFLOW_FLAG != 0 {
+            let first = self.ident()?;
+            let mut entry = [0; size_of::<u32>()];
+            entry.copy_from_slice(self.bytes(size_of::<u32>())?);
+            let len = u32::from_le_bytes(entry) as usize;
+
+            LeafValue::Overflow { first, len }
+        } else {
+            LeafValue::Inline(decode(self.bytes(value_size as usize)?)?)
+        };
+
+        Some((key, value))
+    }
+
+    /// the key length, right child and key of an inner cell
+    fn inner_entry<K: SearchKey>(&mut self) -> Option<(K, NodeIdent)> {
+        let key_size = self.u16()? as usize;
+        let child = self.ident()?;
+        let key = decode(self.bytes(key_size)?)?;
+
+        Some((key, child))
+    }
+}
+
+impl<K, T> LeafNode<K, T>
 where
-    T: Sized,
-    T: Debug,
+    K: SearchKey,
+    T: Value,
 {
-    fn to_bytes(&self) -> [u8; BLOCK_SIZE] {
-        let mut bytes: [u8; BLOCK_SIZE] = [0; BLOCK_SIZE];
+    /// whether the leaf fits into a single block
+    pub fn fits(&self, block_size: usize) -> bool {
+        LEAF_HEADER_SIZE + self.cell_sizes().iter().sum::<usize>() <= payload_size(block_size)
+    }
+
+    /// whether the leaf uses less than a quarter of its block and should be merged with a sibling
+    pub fn underflows(&self, block_size: usize) -> bool {
+        self.cell_sizes().iter().sum::<usize>() < (payload_size(block_size) - LEAF_HEADER_SIZE) / 4
+    }
+}
+
+impl<K, T> LeafNode<K, T>
+where
+    K: SearchKey,
+    T: Value,
+{
+    pub fn to_bytes(&self, block_size: usize) -> Block {
+        let mut bytes = vec![0; block_size];
         let mut index = 0;
 
-        for &key in &self.separators {
-            let entry = key.to_le_bytes();
-            let slice = &mut bytes[index..index + size_of::<SearchKey>()];
-            slice.copy_from_slice(&entry);
-            index += size_of::<SearchKey>();
-        }
-        for &ident in &self.children {
-            let entry = ident.to_le_bytes();
-            let slice = &mut bytes[index..index + size_of::<NodeIdent>()];
-            slice.copy_from_slice(&entry);
+        bytes[index] = LEAF_TAG;
+        index += size_of::<u8>();
+
+        bytes[index..index + size_of::<u16>()]
+            .copy_from_slice(&(self.keys.len() as u16).to_le_bytes());
+        index += size_of::<u16>();
+
+        for link in [self.next, self.prev] {
+            bytes[index..index + size_of::<NodeIdent>()].copy_from_slice(&link.to_le_bytes());
             index += size_of::<NodeIdent>();
         }
 
+        let cells = self
+            .keys
+            .iter()
+            .zip(&self.values)
+            .map(|(key, value)| {
+                let value_size = match value {
+                    LeafValue::Inline(value) => value.encoded_size() as u16,
+                    LeafValue::Overflow { .. } => OVERFLOW_FLAG,
+                };
+
+                let mut cell = vec![];
+                cell.extend_from_slice(&(key.encoded_size() as u16).to_le_bytes());
+                cell.extend_from_slice(&value_size.to_le_bytes());
+                cell.extend_from_slice(&key.to_vec());
+
+                match value {
+                    LeafValue::Inline(value) => cell.extend_from_slice(&value.to_vec()),
+                    LeafValue::Overflow { first, len } => {
+                        cell.extend_from_slice(&first.to_le_bytes());
+                        cell.extend_from_slice(&(*len as u32).to_le_bytes());
+                    }
+                }
+
+                cell
+            })
+            .collect();
+        pack_cells(&mut bytes, LEAF_HEADER_SIZE, cells);
+
         bytes
     }
 
-    fn from_bytes(block: [u8; BLOCK_SIZE]) -> Self {
-        let mut children: [NodeIdent; S] = [0; S];
-        let mut separators: [SearchKey; S] = [0; S];
+    /// decodes the leaf stored in block `ident`, which has to start with the leaf tag
+    pub fn from_bytes(ident: NodeIdent, block: &[u8]) -> Result<Self, NodeStoreError> {
+        let header = size_of::<u8>() + size_of::<u16>();
+        let next = read_ident(block, header);
+        let prev = read_ident(block, header + size_of::<NodeIdent>());
 
-        let mut index = 0;
-        for i in 0..separators.len() {
-            let slice = &block[index..index + size_of::<SearchKey>()];
-            let mut entry = [0; size_of::<SearchKey>()];
-            entry.copy_from_slice(slice);
+        let mut keys = vec![];
+        let mut values = vec![];
 
-            separators[i] = SearchKey::from_le_bytes(entry);
-            index += size_of::<SearchKey>();
+        for (i, offset) in cell_offsets(ident, block, LEAF_HEADER_SIZE)?
+            .into_iter()
+            .enumerate()
+        {
+            let (key, value) = CellReader::new(block, offset)
+                .leaf_entry()
+                .ok_or_else(|| malformed(ident, format!("cell {i} is malformed")))?;
+            keys.push(key);
+            values.push(value);
         }
 
-        for i in 0..children.len() {
-            let slice = &block[index..index + size_of::<NodeIdent>()];
-            let mut entry = [0; size_of::<NodeIdent>()];
-            entry.copy_from_slice(slice);
+        Ok(LeafNode {
+            keys,
+            values,
+            next,
+            prev,
+        })
+    }
+}
 
-            children[i] = NodeIdent::from_le_bytes(entry);
-            index += size_of::<NodeIdent>();
-        }
+impl<K> InnerNode<K>
+where
+    K: SearchKey,
+{
+    /// whether the node fits into a single block
+    pub fn fits(&self, block_size: usize) -> bool {
+        INNER_HEADER_SIZE + self.cell_sizes().iter().sum::<usize>() <= payload_size(block_size)
+    }
 
-        let size = {
-            let mut i = 0;
-            while i < S && separators[i] != 0 {
-                i += 1;
-            }
-            i
-        };
+    /// whether the node uses less than a quarter of its block and should be merged with a sibling
+    pub fn underflows(&self, block_size: usize) -> bool {
+        self.cell_sizes().iter().sum::<usize>() < (payload_size(block_size) - INNER_HEADER_SIZE) / 4
+    }
+}
 
-        Node {
+impl<K> InnerNode<K>
+where
+    K: SearchKey,
+{
+    pub fn to_bytes(&self, block_size: usize) -> Block {
+        let mut bytes = vec![0; block_size];
+        let mut index = 0;
+
+        bytes[index] = INNER_TAG;
+        index += size_of::<u8>();
+
+        bytes[index..index + size_of::<u16>()]
+            .copy_from_slice(&(self.separators.len() as u16).to_le_bytes());
+        index += size_of::<u16>();
+
+        // nodes emptied while rebalancing have no children, but may still be written back
+        let leftmost = self.children.first().copied().unwrap_or(0);
+        bytes[index..index + size_of::<NodeIdent>()].copy_from_slice(&leftmost.to_le_bytes());
+
+        // every separator is stored together with the subtree to its right
+        let cells = self
+            .separators
+            .iter()
+            .zip(self.children.iter().skip(1))
+            .map(|(key, child)| {
+                let mut cell = vec![];
+                cell.extend_from_slice(&(key.encoded_size() as u16).to_le_bytes());
+                cell.extend_from_slice(&child.to_le_bytes());
+                cell.extend_from_slice(&key.to_vec());
+                cell
+            })
+            .collect();
+        pack_cells(&mut bytes, INNER_HEADER_SIZE, cells);
+
+        bytes
+    }
+
+    /// decodes the inner node stored in block `ident`, which has to start with the inner tag
+    pub fn from_bytes(ident: NodeIdent, block: &[u8]) -> Result<Self, NodeStoreError> {
+        let mut separators = vec![];
+        let mut children = vec![read_ident(block, size_of::<u8>() + size_of::<u16>())];
+
+        for (i, offset) in cell_offsets(ident, block, INNER_HEADER_SIZE)?
+            .into_iter()
+            .enumerate()
+        {
+            let (key, child) = CellReader::new(block, offset)
+                .inner_entry()
+                .ok_or_else(|| malformed(ident, format!("cell {i} is malformed")))?;
+            separators.push(key);
+            children.push(child);
+        }
+
+        Ok(InnerNode {
             separators,
             children,
-            size,
-            phantom: PhantomData::<T>,
+        })
+    }
+}
+
+/// reads `len` bytes back from the overflow chain starting at `first`, getting its blocks from
+/// `get_block`
+pub fn read_overflow(
+    first: NodeIdent,
+    len: usize,
+    mut get_block: impl FnMut(usize) -> Result<Block, NodeStoreError>,
+) -> Result<Vec<u8>, NodeStoreError> {
+    let mut bytes = Vec::with_capacity(len);
+    let mut index = first;
+
+    while bytes.len() < len {
+        if index == 0 {
+            return Err(NodeStoreError::InvalidReference);
         }
+
+        let block = get_block(index as usize)?;
+        let next = overflow_link(index, &block)?;
+        let chunk = overflow_payload_size(block.len()).min(len - bytes.len());
+        bytes.extend_from_slice(&block[OVERFLOW_HEADER_SIZE..OVERFLOW_HEADER_SIZE + chunk]);
+        index = next;
     }
+
+    Ok(bytes)
 }
 
-#[derive(Debug)]
+/// the block following the overflow block `ident` in its chain, 0 if it is the last one
+pub fn overflow_link(ident: NodeIdent, block: &[u8]) -> Result<NodeIdent, NodeStoreError> {
+    match block[0] {
+        OVERFLOW_TAG => Ok(read_ident(block, size_of::<u8>())),
+        tag => Err(malformed(
+            ident,
+            format!("{} instead of an overflow block", describe(tag)),
+        )),
+    }
+}
+
+/// what a block starting with `tag` holds
+fn describe(tag: u8) -> String {
+    match tag {
+        LEAF_TAG => "a leaf".to_string(),
+        INNER_TAG => "an inner node".to_string(),
+        FREE_TAG => "a free block".to_string(),
+        OVERFLOW_TAG => "an overflow block".to_string(),
+        tag => format!("unknown block type {tag}"),
+    }
+}
+
+// Nodes, free blocks and overflow blocks can't implement ByteSerialize, since a block may hold
+// something else than was asked for, e.g. if the block was freed, or be malformed.
+impl<K, T> Node<K, T>
+where
+    K: SearchKey,
+    T: Value,
+{
+    pub fn to_bytes(&self, block_size: usize) -> Block {
+        match self {
+            Node::Leaf(leaf) => leaf.to_bytes(block_size),
+            Node::Inner(inner) => inner.to_bytes(block_size),
+        }
+    }
+
+    /// decodes the node stored in block `ident`, failing if the block holds something else or
+    /// its cells don't lie within the block
+    pub fn from_bytes(ident: NodeIdent, block: &[u8]) -> Result<Self, NodeStoreError> {
+        match block[0] {
+            LEAF_TAG => LeafNode::from_bytes(ident, block).map(Node::Leaf),
+            INNER_TAG => InnerNode::from_bytes(ident, block).map(Node::Inner),
+            tag => Err(malformed(
+                ident,
+                format!("{} instead of a node", describe(tag)),
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Metadata {
-    pub fanout: usize,
     pub block_size: usize,
     pub node_ident_size: usize,
     pub search_key_size: usize,
     pub node_ctr: NodeIdent,
     pub root_node: NodeIdent,
+    /// first block of the chain of freed blocks, 0 if there are none
+    pub free_list_head: NodeIdent,
+    /// root of the page table of a shadow paged file, 0 if the file uses a write-ahead log. Set by
+    /// the store when committing.
+    pub page_table: NodeIdent,
 }
 
 impl ByteSerialize for Metadata {
-    fn to_bytes(&self) -> [u8; BLOCK_SIZE] {
-        let mut block = [0; BLOCK_SIZE];
-        let mut index = 0;
+    fn to_bytes(&self, block_size: usize) -> Block {
+        let mut block = vec![0; block_size];
+        block[..MAGIC.len()].copy_from_slice(&MAGIC);
+        block[MAGIC.len()..FORMAT_HEADER_SIZE].copy_from_slice(&FORMAT_VERSION.to_le_bytes());
+        let mut index = FORMAT_HEADER_SIZE;
 
-        let rest = [
-            self.fanout,
-            self.block_size,
-            self.node_ident_size,
-            self.search_key_size,
-        ];
+        let rest = [self.block_size, self.node_ident_size, self.search_key_size];
 
         for &num in rest.iter() {
             let end = index + size_of::<usize>();
@@ -113,20 +572,28 @@ impl ByteSerialize for Metadata {
 
         let root_slice = &mut block[index..index + size_of::<NodeIdent>()];
         root_slice.copy_from_slice(&self.root_node.to_le_bytes());
+        index += size_of::<NodeIdent>();
+
+        let free_slice = &mut block[index..index + size_of::<NodeIdent>()];
+        free_slice.copy_from_slice(&self.free_list_head.to_le_bytes());
+        index += size_of::<NodeIdent>();
+
+        let table_slice = &mut block[index..index + size_of::<NodeIdent>()];
+        table_slice.copy_from_slice(&self.page_table.to_le_bytes());
 
         block
     }
 
-    fn from_bytes(block: [u8; BLOCK_SIZE]) -> Self {
-        let mut index = 0;
-        let mut base_params = [0usize; 4];
+    fn from_bytes(block: &[u8]) -> Self {
+        let mut index = FORMAT_HEADER_SIZE;
+        let mut base_params = [0usize; 3];
 
-        for i in 0..base_params.len() {
+        for param in base_params.iter_mut() {
             let slice = &block[index..index + size_of::<usize>()];
             let mut entry = [0; size_of::<usize>()];
             entry.copy_from_slice(slice);
 
-            base_params[i] = usize::from_le_bytes(entry);
+            *param = usize::from_le_bytes(entry);
             index += size_of::<usize>();
         }
 
@@ -140,164 +607,1299 @@ impl ByteSerialize for Metadata {
         let mut entry = [0u8; size_of::<NodeIdent>()];
         entry.copy_from_slice(root_slice);
         let root = NodeIdent::from_le_bytes(entry);
+        index += size_of::<NodeIdent>();
+
+        let free_slice = &block[index..index + size_of::<NodeIdent>()];
+        let mut entry = [0u8; size_of::<NodeIdent>()];
+        entry.copy_from_slice(free_slice);
+        let free_list_head = NodeIdent::from_le_bytes(entry);
+        index += size_of::<NodeIdent>();
 
-        let data = Metadata {
-            fanout: base_params[0],
-            block_size: base_params[1],
-            node_ident_size: base_params[2],
-            search_key_size: base_params[3],
+        let table_slice = &block[index..index + size_of::<NodeIdent>()];
+        let mut entry = [0u8; size_of::<NodeIdent>()];
+        entry.copy_from_slice(table_slice);
+        let page_table = NodeIdent::from_le_bytes(entry);
+
+        Metadata {
+            block_size: base_params[0],
+            node_ident_size: base_params[1],
+            search_key_size: base_params[2],
             node_ctr,
             root_node: root,
-        };
-        data
+            free_list_head,
+            page_table,
+        }
     }
 }
 
-pub struct FileStore<T, const S: usize>
+impl Metadata {
+    /// reads the block size from the start of the metadata block, it is needed to read the rest.
+    /// Fails if the file doesn't start with the magic and version of this format.
+    pub fn read_block_size(file: &File) -> Result<usize, LoadError> {
+        let mut header = [0; FORMAT_HEADER_SIZE + size_of::<usize>()];
+        file.read_exact_at(&mut header, 0)
+            .map_err(LoadError::IOError)?;
+        if header[..MAGIC.len()] != MAGIC
+            || header[MAGIC.len()..FORMAT_HEADER_SIZE] != FORMAT_VERSION.to_le_bytes()
+        {
+            return Err(LoadError::UnsupportedFormat);
+        }
+
+        let mut entry = [0; size_of::<usize>()];
+        entry.copy_from_slice(&header[FORMAT_HEADER_SIZE..]);
+        Ok(usize::from_le_bytes(entry))
+    }
+
+    /// reads the metadata from the first block of the file
+    pub fn read(file: &File) -> Result<Self, LoadError> {
+        // the block size is stored at the start of the metadata, so the file tells how to read it
+        let block_size = Metadata::read_block_size(file)?;
+        if !valid_block_size(block_size) {
+            return Err(LoadError::ParameterMismatch);
+        }
+
+        let block = read_block_at(file, 0, block_size).map_err(LoadError::StoreError)?;
+        Ok(Metadata::from_bytes(&block))
+    }
+}
+
+/// a released block. Free blocks form a chain starting at `Metadata::free_list_head`
+pub struct FreeBlock {
+    pub next: NodeIdent,
+}
+
+impl FreeBlock {
+    pub fn to_bytes(&self, block_size: usize) -> Block {
+        let mut block = vec![0; block_size];
+        block[0] = FREE_TAG;
+        block[size_of::<u8>()..size_of::<u8>() + size_of::<NodeIdent>()]
+            .copy_from_slice(&self.next.to_le_bytes());
+        block
+    }
+
+    /// decodes the free block `ident`, which has to start with the free tag
+    pub fn from_bytes(ident: NodeIdent, block: &[u8]) -> Result<Self, NodeStoreError> {
+        match block[0] {
+            FREE_TAG => Ok(FreeBlock {
+                next: read_ident(block, size_of::<u8>()),
+            }),
+            tag => Err(malformed(
+                ident,
+                format!("{} instead of a free block", describe(tag)),
+            )),
+        }
+    }
+}
+
+pub struct FileStore<K, T>
 where
-    T: Sized,
-    T: Debug,
+    K: SearchKey,
+    T: Value,
 {
     file: File,
+    journal: Journal,
+    // blocks written since the last commit. They only reach the file once they are committed
+    pending: BTreeMap<usize, Block>,
+    // committed blocks that could not be written to the file yet
+    unwritten: BTreeMap<usize, Block>,
     node_ctr: NodeIdent,
-    cache: Cache<T, S>,
+    free_list_head: NodeIdent,
+    // allocation state as of the last commit, restored when rolling back
+    committed_node_ctr: NodeIdent,
+    committed_free_list_head: NodeIdent,
+    cache: Cache<K, T>,
+    /// amount of commits since the store was opened
+    commits: SnapshotId,
+    /// snapshots that are still alive, by the commit they were taken after
+    snapshots: BTreeMap<SnapshotId, Snapshot>,
+    // contents of blocks before they were overwritten in place while snapshots were alive, together
+    // with the commit that overwrote them. Only used with a write-ahead log, shadow paging keeps
+    // the old blocks in the file instead.
+    versions: BTreeMap<usize, Vec<(SnapshotId, Block)>>,
+    block_size: usize,
+    // makes syncing the metadata of a shadow paged commit fail after it was written
+    #[cfg(test)]
+    fail_metadata_sync: bool,
+}
+
+struct Snapshot {
+    readers: usize,
+    metadata: Metadata,
 }
 
+/// how a `FileStore` makes its commits durable
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Durability {
+    /// changed blocks are appended to a log next to the tree file before they are overwritten in
+    /// place
+    #[default]
+    WriteAheadLog,
+    /// changed blocks are written to unused places in the tree file and published all at once by
+    /// pointing the metadata at a new page table
+    ShadowPaging,
+}
+
+enum Journal {
+    Wal(Wal),
+    Shadow(PageTable),
+}
+
+/// size of the log in blocks after which its commits are synced to the tree file and the log is
+/// emptied
+const CHECKPOINT_BLOCKS: u64 = 1024;
+
 #[derive(Debug)]
 pub enum LoadError {
     ParameterMismatch,
+    /// the file is not a tree file, or was written in a different format, e.g. by the fixed-size
+    /// nodes of the first versions of the tree
+    UnsupportedFormat,
     IOError(io::Error),
+    StoreError(NodeStoreError),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::ParameterMismatch => {
+                write!(f, "the file was written with different parameters")
+            }
+            LoadError::UnsupportedFormat => {
+                write!(
+                    f,
+                    "the file is not a tree file of format version {FORMAT_VERSION}"
+                )
+            }
+            LoadError::IOError(e) => write!(f, "io error: {e}"),
+            LoadError::StoreError(e) => write!(f, "node store error: {e}"),
+        }
+    }
 }
 
-impl<T, const S: usize> FileStore<T, S>
+impl Error for LoadError {}
+
+impl<K, T> FileStore<K, T>
 where
-    T: Debug,
-    T: Sized,
+    K: SearchKey,
+    T: Value,
 {
-    pub fn new(file_name: String) -> Result<Self, std::io::Error> {
+    /// creates a new file storing blocks of `block_size` bytes, which has to be a power of two
+    /// between `MIN_BLOCK_SIZE` and `MAX_BLOCK_SIZE`
+    pub fn new(
+        file_name: String,
+        block_size: usize,
+        durability: Durability,
+        cache: CacheConfig,
+    ) -> Result<Self, std::io::Error> {
+        if !valid_block_size(block_size) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid block size {block_size}"),
+            ));
+        }
+
         let file = OpenOptions::new()
             .read(true)
             .write(true)
             .append(false)
             .create(true)
-            .open(file_name)?;
+            .truncate(true)
+            .open(&file_name)?;
+
+        // a log left behind by an older tree in the same place must not be replayed
+        let journal = match durability {
+            Durability::WriteAheadLog => {
+                let mut wal = Wal::open(&file_name)?;
+                wal.truncate()?;
+                Journal::Wal(wal)
+            }
+            Durability::ShadowPaging => {
+                match fs::remove_file(Wal::path(&file_name)) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+                Journal::Shadow(PageTable::new(block_size))
+            }
+        };
 
-        Ok(FileStore::<T, S> {
+        Ok(FileStore::<K, T> {
             file,
+            journal,
+            pending: BTreeMap::new(),
+            unwritten: BTreeMap::new(),
             node_ctr: 0,
-            cache: Cache::<T, S>::new(),
+            free_list_head: 0,
+            committed_node_ctr: 0,
+            committed_free_list_head: 0,
+            cache: Cache::<K, T>::new(cache.replacement, cache.capacity.nodes(block_size)),
+            commits: 0,
+            snapshots: BTreeMap::new(),
+            versions: BTreeMap::new(),
+            block_size,
+            #[cfg(test)]
+            fail_metadata_sync: false,
         })
     }
 
-    pub fn get_block(&self, index: usize) -> Result<[u8; BLOCK_SIZE], NodeStoreError> {
-        let mut buf = [0; BLOCK_SIZE];
-        match self
-            .file
-            .read_exact_at(&mut buf, (BLOCK_SIZE * index) as u64)
-        {
-            Ok(()) => Ok(buf),
-            Err(_) => Err(NodeStoreError::InvalidReference),
+    pub fn get_block(&self, index: usize) -> Result<Block, NodeStoreError> {
+        match self.pending.get(&index) {
+            Some(block) => Ok(block.clone()),
+            None => self.get_committed_block(index),
         }
     }
 
-    pub fn set_block(&self, index: usize, block: [u8; BLOCK_SIZE]) -> Result<(), NodeStoreError> {
-        match self.file.write_at(&block, (BLOCK_SIZE * index) as u64) {
-            Ok(i) if i == BLOCK_SIZE => Ok(()),
-            Ok(_i) => Err(NodeStoreError::WriteFailed),
-            Err(_e) => Err(NodeStoreError::WriteFailed),
+    /// stages the block for the next commit
+    pub fn set_block(&mut self, index: usize, block: Block) -> Result<(), NodeStoreError> {
+        self.pending.insert(index, block);
+        Ok(())
+    }
+
+    /// writes the block to the tree file, bypassing the log
+    fn write_block(&self, index: usize, block: &[u8]) -> Result<(), NodeStoreError> {
+        write_block_at(&self.file, index, block)
+    }
+
+    /// reads the block as of the last commit
+    fn get_committed_block(&self, index: usize) -> Result<Block, NodeStoreError> {
+        if let Some(block) = self.unwritten.get(&index) {
+            return Ok(block.clone());
         }
+
+        let place = match &self.journal {
+            Journal::Wal(_) if index > self.node_ctr as usize => {
+                return Err(NodeStoreError::InvalidReference)
+            }
+            Journal::Wal(_) => index,
+            Journal::Shadow(table) => table.get(index).ok_or(NodeStoreError::InvalidReference)?,
+        };
+
+        self.read_placed_block(index, place)
+    }
+
+    /// reads the block `index` from `place` in the file. Checksum errors name the block by its
+    /// index, which differs from its place in shadow paged files.
+    fn read_placed_block(&self, index: usize, place: usize) -> Result<Block, NodeStoreError> {
+        read_block_at(&self.file, place, self.block_size).map_err(|error| match error {
+            NodeStoreError::Corrupted {
+                expected, actual, ..
+            } => NodeStoreError::Corrupted {
+                block: index as NodeIdent,
+                expected,
+                actual,
+            },
+            error => error,
+        })
     }
 
-    pub fn load(file_name: String) -> Result<(Self, NodeIdent), LoadError> {
+    pub fn load(file_name: String, cache: CacheConfig) -> Result<(Self, NodeIdent), LoadError> {
         let file = OpenOptions::new()
             .read(true)
             .write(true)
             .append(false)
             .create(false)
-            .open(file_name)
-            .map_err(|e| LoadError::IOError(e))?;
+            .open(&file_name)
+            .map_err(LoadError::IOError)?;
 
-        let mut buf = [0; BLOCK_SIZE];
-        file.read_exact_at(&mut buf, 0)
-            .map_err(|e| LoadError::IOError(e))?;
+        // bring the file up to the last commit before reading anything else from it. Shadow paged
+        // files are consistent on their own, a stray log next to one is not replayed over its
+        // blocks. The metadata of a log's first commit may only be in the log.
+        let shadow_paged = Metadata::read(&file).is_ok_and(|metadata| metadata.page_table != 0);
+        let log_exists = fs::exists(Wal::path(&file_name)).map_err(LoadError::IOError)?;
+        let wal = if !shadow_paged && log_exists {
+            let mut wal = Wal::open(&file_name).map_err(LoadError::IOError)?;
+            for (index, block) in wal.committed_blocks().map_err(LoadError::IOError)? {
+                write_block_at(&file, index, &block).map_err(LoadError::StoreError)?;
+            }
+            file.sync_data().map_err(LoadError::IOError)?;
+            wal.truncate().map_err(LoadError::IOError)?;
+            Some(wal)
+        } else {
+            None
+        };
 
-        let metadata = Metadata::from_bytes(buf);
-        if metadata.block_size != BLOCK_SIZE
-            || metadata.search_key_size != size_of::<SearchKey>()
+        let metadata = Metadata::read(&file)?;
+        let block_size = metadata.block_size;
+        if metadata.search_key_size != K::FIXED_SIZE.unwrap_or(0)
             || metadata.node_ident_size != size_of::<NodeIdent>()
         {
             return Err(LoadError::ParameterMismatch);
         }
 
+        let journal = match (metadata.page_table, wal) {
+            (0, Some(wal)) => Journal::Wal(wal),
+            (0, None) => Journal::Wal(Wal::open(&file_name).map_err(LoadError::IOError)?),
+            (root, _) => Journal::Shadow(
+                PageTable::load(&file, root, metadata.node_ctr, block_size)
+                    .map_err(LoadError::StoreError)?,
+            ),
+        };
+
         Ok((
-            FileStore::<T, S> {
+            FileStore::<K, T> {
                 file,
+                journal,
+                pending: BTreeMap::new(),
+                unwritten: BTreeMap::new(),
                 node_ctr: metadata.node_ctr,
-                cache: Cache::<T, S>::new(),
+                free_list_head: metadata.free_list_head,
+                committed_node_ctr: metadata.node_ctr,
+                committed_free_list_head: metadata.free_list_head,
+                cache: Cache::<K, T>::new(cache.replacement, cache.capacity.nodes(block_size)),
+                commits: 0,
+                snapshots: BTreeMap::new(),
+                versions: BTreeMap::new(),
+                block_size,
+                #[cfg(test)]
+                fail_metadata_sync: false,
             },
             metadata.root_node,
         ))
     }
+
+    /// makes sure the node is cached, reading it from its block if it isn't
+    fn load_node(&mut self, ident: NodeIdent) -> Result<(), NodeStoreError> {
+        if self.cache.has_node(ident) {
+            return Ok(());
+        }
+
+        let block = self.get_block(ident as usize)?;
+        let node = Node::from_bytes(ident, &block)?;
+
+        let evicted = self.cache.cache_node(ident, node);
+        self.write_back(evicted)
+    }
+
+    /// stages the nodes that left the cache for the next commit
+    fn write_back(&mut self, nodes: Vec<(NodeIdent, Node<K, T>)>) -> Result<(), NodeStoreError> {
+        let mut result = Ok(());
+
+        for (id, node) in nodes {
+            let block = node.to_bytes(self.block_size);
+            if let Err(e) = self.set_block(id as usize, block) {
+                // the node would be lost otherwise, so it goes back into the cache, even if that
+                // leaves the cache above its capacity. A later write back can try again.
+                self.cache.restore(id, node);
+                result = Err(e);
+            }
+        }
+
+        result
+    }
+
+    /// returns the index of an unused block, reusing freed blocks before growing the file
+    pub fn allocate_block(&mut self) -> Result<NodeIdent, NodeStoreError> {
+        if self.free_list_head != 0 {
+            let index = self.free_list_head;
+            self.free_list_head =
+                FreeBlock::from_bytes(index, &self.get_block(index as usize)?)?.next;
+            return Ok(index);
+        }
+
+        self.node_ctr += 1;
+        Ok(self.node_ctr)
+    }
+
+    fn release_block(&mut self, index: NodeIdent) -> Result<(), NodeStoreError> {
+        let block = FreeBlock {
+            next: self.free_list_head,
+        }
+        .to_bytes(self.block_size);
+        self.set_block(index as usize, block)?;
+        self.free_list_head = index;
+
+        Ok(())
+    }
 }
 
-impl<T, const S: usize> NodeStore<T, S> for FileStore<T, S>
+impl<K, T> NodeStore<K, T> for FileStore<K, T>
 where
-    T: Sized,
-    T: Debug,
+    K: SearchKey,
+    T: Value,
 {
-    fn get_node(&mut self, ident: NodeIdent) -> Result<&mut Node<T, S>, NodeStoreError> {
-        if self.cache.has_node(ident) {
-            return Ok(&mut self.cache.get_node(ident).unwrap().node);
+    fn get_node(&mut self, ident: NodeIdent) -> Result<RefMut<'_, Node<K, T>>, NodeStoreError> {
+        self.load_node(ident)?;
+
+        // the node was cached by `load_node`, this should not fail
+        Ok(self.cache.get_node_mut(ident).unwrap())
+    }
+
+    fn read_node(&mut self, ident: NodeIdent) -> Result<Ref<'_, Node<K, T>>, NodeStoreError> {
+        self.load_node(ident)?;
+
+        Ok(self.cache.get_node(ident).unwrap())
+    }
+
+    fn pin_node(&mut self, ident: NodeIdent) -> Result<PageGuard<K, T>, NodeStoreError> {
+        self.load_node(ident)?;
+
+        Ok(self.cache.pin(ident).unwrap())
+    }
+
+    fn store_node(&mut self, node: Node<K, T>) -> Result<NodeIdent, NodeStoreError> {
+        let ident = self.allocate_block()?;
+
+        let block = node.to_bytes(self.block_size);
+        self.set_block(ident as usize, block).map(|_| ident)
+    }
+
+    fn free_node(&mut self, ident: NodeIdent) -> Result<(), NodeStoreError> {
+        // the node is gone, there is no point in writing it back
+        self.cache.remove(ident);
+        self.release_block(ident)
+    }
+
+    fn store_overflow(&mut self, bytes: &[u8]) -> Result<NodeIdent, NodeStoreError> {
+        let chunks: Vec<&[u8]> = bytes
+            .chunks(overflow_payload_size(self.block_size))
+            .collect();
+        let mut blocks = Vec::with_capacity(chunks.len());
+        for _ in 0..chunks.len() {
+            blocks.push(self.allocate_block()?);
         }
 
-        let block = { self.get_block(ident.abs() as usize)? };
-        let node: Node<T, S> = Node::from_bytes(block);
+        for (i, chunk) in chunks.iter().enumerate() {
+            let next = blocks.get(i + 1).copied().unwrap_or(0);
 
-        if let Some((ident, node)) = { self.cache.cache_node(ident, node) } {
-            let block = node.to_bytes();
-            self.set_block(ident.abs() as usize, block)?;
+            let mut block = vec![0; self.block_size];
+            block[0] = OVERFLOW_TAG;
+            block[size_of::<u8>()..OVERFLOW_HEADER_SIZE].copy_from_slice(&next.to_le_bytes());
+            block[OVERFLOW_HEADER_SIZE..OVERFLOW_HEADER_SIZE + chunk.len()].copy_from_slice(chunk);
+            self.set_block(blocks[i] as usize, block)?;
         }
 
-        // we just inserted the node when calling cache, this should not fail
-        Ok(&mut self.cache.get_node(ident).unwrap().node)
+        Ok(blocks.first().copied().unwrap_or(0))
     }
 
-    fn store_node(&mut self, node: Node<T, S>, is_leaf: bool) -> Result<NodeIdent, NodeStoreError> {
-        self.node_ctr += 1;
-        let ident = self.node_ctr;
-
-        let block = node.to_bytes();
-        self.set_block(ident as usize, block)
-            .map(|_| if is_leaf { ident } else { -ident })
-    }
-
-    fn print_stored_nodes(&mut self, root: NodeIdent) -> () {
-        let mut node_stack: Vec<NodeIdent> = vec![root];
-        while let Some(i) = node_stack.pop() {
-            let node = self.get_node(i).unwrap();
-            println!("{}", node.to_graphviz(&i));
-            if i < 0 {
-                node.children[0..node.size + 1]
-                    .iter()
-                    .for_each(|i| node_stack.push(*i));
+    fn load_overflow(&mut self, first: NodeIdent, len: usize) -> Result<Vec<u8>, NodeStoreError> {
+        read_overflow(first, len, |index| self.get_block(index))
+    }
+
+    fn free_overflow(&mut self, first: NodeIdent) -> Result<(), NodeStoreError> {
+        let mut index = first;
+        while index != 0 {
+            let next = overflow_link(index, &self.get_block(index as usize)?)?;
+            self.release_block(index)?;
+            index = next;
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), NodeStoreError> {
+        // clean nodes are the same as their blocks, they are simply dropped
+        let nodes = self.cache.drain();
+        self.write_back(nodes)
+    }
+
+    fn set_metadata(&mut self, data: Metadata) -> Result<(), NodeStoreError> {
+        let block = data.to_bytes(self.block_size);
+        self.set_block(0, block)
+    }
+
+    fn has_changes(&self) -> bool {
+        !self.pending.is_empty()
+            || self.cache.is_dirty()
+            || self.node_ctr != self.committed_node_ctr
+            || self.free_list_head != self.committed_free_list_head
+    }
+
+    fn commit(&mut self) -> Result<(), NodeStoreError> {
+        // dirty nodes were changed in the cache, so their current state is part of the commit
+        let cached: Vec<(NodeIdent, Block)> = self
+            .cache
+            .dirty_nodes()
+            .map(|(id, node)| (id, node.to_bytes(self.block_size)))
+            .collect();
+        for (id, block) in cached {
+            self.pending.insert(id as usize, block);
+        }
+        self.cache.mark_clean();
+
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+
+        let log_size = match &mut self.journal {
+            Journal::Wal(wal) => {
+                wal.append(&self.pending)?;
+                wal.len()
+            }
+            Journal::Shadow(table) => {
+                // the metadata publishes the new table, so it is written last and in place
+                let mut metadata = Metadata::from_bytes(&match self.pending.remove(&0) {
+                    Some(block) => block,
+                    None => read_block_at(&self.file, 0, self.block_size)?,
+                });
+                metadata.page_table = table.stage(&self.file, &self.pending, metadata.node_ctr)?;
+
+                if let Err(e) = write_block_at(&self.file, 0, &metadata.to_bytes(self.block_size)) {
+                    table.revert();
+                    return Err(e);
+                }
+                // the metadata may be on disk even though the sync failed, so a crash could still
+                // publish the staged table. Its places must not be overwritten by later commits.
+                #[cfg(test)]
+                let synced = match self.fail_metadata_sync {
+                    true => Err(io::Error::other("injected sync failure")),
+                    false => self.file.sync_data(),
+                };
+                #[cfg(not(test))]
+                let synced = self.file.sync_data();
+                if synced.is_err() {
+                    table.abandon();
+                    return Err(NodeStoreError::WriteFailed);
+                }
+                self.commits += 1;
+                table.publish((!self.snapshots.is_empty()).then_some(self.commits));
+
+                self.pending.clear();
+                self.committed_node_ctr = self.node_ctr;
+                self.committed_free_list_head = self.free_list_head;
+                return Ok(());
+            }
+        };
+        self.committed_node_ctr = self.node_ctr;
+        self.committed_free_list_head = self.free_list_head;
+        self.commits += 1;
+
+        // snapshots may still need the blocks about to be overwritten. A snapshot reads the first
+        // version replaced after it was taken, so a block replaced since the newest snapshot
+        // already has every version that will ever be read.
+        if let Some(&newest) = self.snapshots.keys().next_back() {
+            for &index in self.pending.keys() {
+                let replaced = self
+                    .versions
+                    .get(&index)
+                    .and_then(|versions| versions.last())
+                    .is_some_and(|(commit, _)| *commit > newest);
+                if replaced {
+                    continue;
+                }
+
+                if let Ok(block) = self.get_committed_block(index) {
+                    let versions = self.versions.entry(index).or_default();
+                    versions.push((self.commits, block));
+                }
             }
         }
+
+        // the commit is safe in the log now, so the blocks may be overwritten in place. Blocks
+        // that fail to be written are kept around and tried again with the next commit.
+        self.unwritten.append(&mut self.pending);
+        let blocks = mem::take(&mut self.unwritten);
+        for (index, block) in blocks {
+            if self.write_block(index, &block).is_err() {
+                self.unwritten.insert(index, block);
+            }
+        }
+
+        // the commit is durable either way, a failed checkpoint is tried again after the next one
+        if log_size > CHECKPOINT_BLOCKS * self.block_size as u64 {
+            let _ = self.checkpoint();
+        }
+
+        Ok(())
     }
 
-    fn flush(&mut self) -> () {
-        let nodes: Vec<(NodeIdent, CacheItem<T, S>)> = self.cache.drain().collect();
-        nodes.iter().for_each(|(id, item)| {
-            let node_block = item.node.to_bytes();
-            self.set_block(id.abs() as usize, node_block).unwrap();
-        });
+    fn rollback(&mut self) {
+        // operations failing before they change anything, e.g. inserting a duplicate key, keep
+        // the cache warm
+        if !self.has_changes() {
+            return;
+        }
+
+        // committed nodes can be read again, so the whole cache can be dropped
+        self.cache.clear();
+        self.pending.clear();
+        self.node_ctr = self.committed_node_ctr;
+        self.free_list_head = self.committed_free_list_head;
     }
 
-    fn set_metadata(&mut self, data: Metadata) {
-        let block = data.to_bytes();
-        self.set_block(0, block).unwrap();
+    fn checkpoint(&mut self) -> Result<(), NodeStoreError> {
+        // shadow paged commits are synced to the tree file right away
+        let Journal::Wal(wal) = &mut self.journal else {
+            return Ok(());
+        };
+
+        // the log is the only place holding the unwritten blocks
+        if !self.unwritten.is_empty() {
+            return Err(NodeStoreError::WriteFailed);
+        }
+
+        self.file
+            .sync_data()
+            .map_err(|_| NodeStoreError::WriteFailed)?;
+        wal.truncate().map_err(|_| NodeStoreError::WriteFailed)
     }
 
     fn node_ctr(&self) -> NodeIdent {
         self.node_ctr
     }
+
+    fn free_list_head(&self) -> NodeIdent {
+        self.free_list_head
+    }
+
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn set_cache_capacity(&mut self, capacity: CacheCapacity) -> Result<(), NodeStoreError> {
+        let evicted = self.cache.resize(capacity.nodes(self.block_size));
+        self.write_back(evicted)
+    }
+
+    fn snapshot(&mut self) -> Result<(SnapshotId, Metadata), NodeStoreError> {
+        let metadata = Metadata::from_bytes(&self.get_committed_block(0)?);
+
+        let snapshot = self.snapshots.entry(self.commits).or_insert(Snapshot {
+            readers: 0,
+            metadata: metadata.clone(),
+        });
+        snapshot.readers += 1;
+
+        Ok((self.commits, metadata))
+    }
+
+    fn snapshot_block(
+        &mut self,
+        snapshot: SnapshotId,
+        index: usize,
+    ) -> Result<Block, NodeStoreError> {
+        let metadata = match self.snapshots.get(&snapshot) {
+            Some(snapshot) => &snapshot.metadata,
+            None => return Err(NodeStoreError::InvalidReference),
+        };
+
+        match &self.journal {
+            Journal::Wal(_) => {
+                // the first version overwritten after the snapshot was taken is the one it saw
+                let version = self.versions.get(&index).and_then(|versions| {
+                    versions
+                        .iter()
+                        .find(|(commit, _)| *commit > snapshot)
+                        .map(|(_, block)| block.clone())
+                });
+
+                match version {
+                    Some(block) => Ok(block),
+                    None => self.get_committed_block(index),
+                }
+            }
+            Journal::Shadow(_) => {
+                let place = PageTable::get_committed(
+                    &self.file,
+                    metadata.page_table,
+                    metadata.node_ctr,
+                    self.block_size,
+                    index,
+                )?;
+
+                self.read_placed_block(index, place)
+            }
+        }
+    }
+
+    fn release_snapshot(&mut self, snapshot: SnapshotId) {
+        if let Some(entry) = self.snapshots.get_mut(&snapshot) {
+            entry.readers -= 1;
+            if entry.readers == 0 {
+                self.snapshots.remove(&snapshot);
+            }
+        }
+
+        // blocks replaced by a commit are only needed by snapshots taken before it
+        let oldest = self.snapshots.keys().next().copied();
+        match &mut self.journal {
+            Journal::Wal(_) => {
+                self.versions.retain(|_, versions| {
+                    versions.retain(|(commit, _)| oldest.is_some_and(|oldest| *commit > oldest));
+                    !versions.is_empty()
+                });
+            }
+            Journal::Shadow(table) => table.release(oldest),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use std::{cell::RefCell, ops::Bound, rc::Rc};
+
+    use super::*;
+    use crate::types::{
+        cache::as_leaf,
+        node_store::SharedNodeStore,
+        range::Range,
+        test_util::{assert_matches_model, TempFile},
+        tree::{Tree, TreeError},
+        verify::Violation,
+    };
+
+    fn metadata(store: &FileStore<u32, u32>) -> Metadata {
+        Metadata {
+            block_size: store.block_size(),
+            node_ident_size: size_of::<NodeIdent>(),
+            search_key_size: size_of::<u32>(),
+            node_ctr: store.node_ctr(),
+            root_node: 1,
+            free_list_head: 0,
+            page_table: 0,
+        }
+    }
+
+    #[test]
+    fn malformed_blocks_are_reported() {
+        let is_malformed = |block: &[u8]| {
+            matches!(
+                Node::<u32, u32>::from_bytes(5, block),
+                Err(NodeStoreError::Malformed { block: 5, .. })
+            )
+        };
+
+        // links whose low byte is a node tag don't turn free or overflow blocks into nodes
+        for next in [1, 2, 257, 258] {
+            assert!(is_malformed(&FreeBlock { next }.to_bytes(128)));
+        }
+        let mut overflow = vec![0; 128];
+        overflow[0] = OVERFLOW_TAG;
+        overflow[1] = 1;
+        assert!(is_malformed(&overflow));
+        assert!(FreeBlock::from_bytes(5, &overflow).is_err());
+        assert!(overflow_link(5, &FreeBlock { next: 1 }.to_bytes(128)).is_err());
+
+        let leaf = Node::<u32, u32>::Leaf(LeafNode {
+            keys: vec![1, 2],
+            values: vec![LeafValue::Inline(1), LeafValue::Inline(2)],
+            next: 0,
+            prev: 0,
+        })
+        .to_bytes(128);
+        assert!(Node::<u32, u32>::from_bytes(5, &leaf).is_ok());
+
+        // more slots than fit into the block
+        let mut block = leaf.clone();
+        block[1..3].copy_from_slice(&200u16.to_le_bytes());
+        assert!(is_malformed(&block));
+        // a cell reaching past the payload
+        let mut block = leaf.clone();
+        let offset = (payload_size(128) - 3) as u16;
+        block[LEAF_HEADER_SIZE..LEAF_HEADER_SIZE + SLOT_SIZE]
+            .copy_from_slice(&offset.to_le_bytes());
+        assert!(is_malformed(&block));
+        // a cell pointing into the slots
+        let mut block = leaf.clone();
+        block[LEAF_HEADER_SIZE..LEAF_HEADER_SIZE + SLOT_SIZE].copy_from_slice(&2u16.to_le_bytes());
+        assert!(is_malformed(&block));
+        // a key that doesn't have the size of every `u32`
+        let mut block = leaf.clone();
+        let cell = read_u16(&leaf, LEAF_HEADER_SIZE) as usize;
+        block[cell..cell + 2].copy_from_slice(&2u16.to_le_bytes());
+        assert!(is_malformed(&block));
+
+        let inner = Node::<u32, u32>::Inner(InnerNode {
+            separators: vec![10],
+            children: vec![1, 2],
+        })
+        .to_bytes(128);
+        let mut block = inner.clone();
+        let cell = read_u16(&inner, INNER_HEADER_SIZE) as usize;
+        block[cell..cell + 2].copy_from_slice(&u16::MAX.to_le_bytes());
+        assert!(is_malformed(&block));
+
+        // a string key that isn't valid UTF-8, behind the key and value length of its cell
+        let mut block = Node::<String, u32>::Leaf(LeafNode {
+            keys: vec!["key".to_string()],
+            values: vec![LeafValue::Inline(1)],
+            next: 0,
+            prev: 0,
+        })
+        .to_bytes(128);
+        let cell = read_u16(&block, LEAF_HEADER_SIZE) as usize;
+        block[cell + 2 * size_of::<u16>()] = 0xff;
+        assert!(matches!(
+            Node::<String, u32>::from_bytes(5, &block),
+            Err(NodeStoreError::Malformed { block: 5, .. })
+        ));
+    }
+
+    #[test]
+    fn torn_metadata_writes_leave_a_loadable_file() {
+        let file = TempFile::new("torn");
+        let block_size = 16384;
+        let mut tree = Tree::<u32, u32>::with_durability(
+            file.path(),
+            block_size,
+            Durability::ShadowPaging,
+            CacheConfig::default(),
+        )
+        .unwrap();
+        for key in 0..100 {
+            tree.insert(key, key).unwrap();
+        }
+        let old = fs::read(file.path()).unwrap()[..SECTOR_SIZE].to_vec();
+        tree.transaction(|tree| (100..200).try_for_each(|key| tree.insert(key, key)))
+            .unwrap();
+        drop(tree);
+
+        let load = || Tree::<u32, u32>::load(file.path(), CacheConfig::default()).unwrap();
+        let tree_file = OpenOptions::new().write(true).open(file.path()).unwrap();
+
+        // only the first sector of the new metadata reached the disk
+        tree_file
+            .write_all_at(&vec![0xff; block_size - SECTOR_SIZE], SECTOR_SIZE as u64)
+            .unwrap();
+        assert_matches_model(&load(), &(0..200).map(|key| (key, key)).collect());
+
+        // the first sector still holds the old metadata
+        tree_file.write_all_at(&old, 0).unwrap();
+        assert_matches_model(&load(), &(0..100).map(|key| (key, key)).collect());
+    }
+
+    #[test]
+    fn failed_metadata_syncs_keep_the_staged_table() {
+        let file = TempFile::new("failed-sync");
+        let mut store = FileStore::<u32, u32>::new(
+            file.path(),
+            128,
+            Durability::ShadowPaging,
+            CacheConfig::default(),
+        )
+        .unwrap();
+        let payload = payload_size(128);
+        let block = |byte| vec![byte; 128];
+
+        let index = store.allocate_block().unwrap() as usize;
+        store.set_block(index, block(1)).unwrap();
+        store.set_metadata(metadata(&store)).unwrap();
+        store.commit().unwrap();
+
+        // the new metadata is written, but it is unknown whether it reached the disk
+        store.fail_metadata_sync = true;
+        store.set_block(index, block(2)).unwrap();
+        assert!(matches!(store.commit(), Err(NodeStoreError::WriteFailed)));
+        store.rollback();
+        store.fail_metadata_sync = false;
+        assert_eq!(
+            store.get_block(index).unwrap()[..payload],
+            block(1)[..payload]
+        );
+
+        let written = Metadata::from_bytes(&read_block_at(&store.file, 0, 128).unwrap());
+        let place = |store: &FileStore<u32, u32>| {
+            PageTable::get_committed(
+                &store.file,
+                written.page_table,
+                written.node_ctr,
+                128,
+                index,
+            )
+            .unwrap()
+        };
+        let staged = place(&store);
+
+        // later commits must leave the table the failed commit may have published intact
+        for byte in 3..10 {
+            store.set_block(index, block(byte)).unwrap();
+            store.set_metadata(metadata(&store)).unwrap();
+            store.commit().unwrap();
+        }
+        assert_eq!(place(&store), staged);
+        assert_eq!(
+            read_block_at(&store.file, staged, 128).unwrap()[..payload],
+            block(2)[..payload]
+        );
+        assert_eq!(
+            store.get_block(index).unwrap()[..payload],
+            block(9)[..payload]
+        );
+    }
+
+    #[test]
+    fn shrinking_the_cache_writes_back_dirty_nodes() {
+        let file = TempFile::new("resize");
+        let cache = CacheConfig {
+            capacity: CacheCapacity::Nodes(64),
+            ..CacheConfig::default()
+        };
+        let mut store =
+            FileStore::<u32, u32>::new(file.path(), 128, Durability::WriteAheadLog, cache).unwrap();
+        let leaf = |value| {
+            Node::Leaf(LeafNode {
+                keys: vec![0],
+                values: vec![LeafValue::Inline(value)],
+                next: 0,
+                prev: 0,
+            })
+        };
+        let idents: Vec<NodeIdent> = (0..20)
+            .map(|i| store.store_node(leaf(i)).unwrap())
+            .collect();
+        store.set_metadata(metadata(&store)).unwrap();
+        store.commit().unwrap();
+
+        // change every node in the cache, as the operations of a transaction do until it commits
+        for (i, &ident) in (0..).zip(&idents) {
+            as_leaf(store.get_node(ident).unwrap()).unwrap().values[0] = LeafValue::Inline(i + 100);
+        }
+        assert_eq!(store.cache.len(), 20);
+        assert_eq!(store.cache.dirty_nodes().count(), 20);
+
+        store
+            .set_cache_capacity(CacheCapacity::Bytes(4 * 128))
+            .unwrap();
+        assert_eq!(store.cache.capacity(), 4);
+        assert_eq!(store.cache.len(), 4);
+        assert_eq!(store.pending.len(), 16);
+        store.commit().unwrap();
+        drop(store);
+
+        let (mut store, _) = FileStore::<u32, u32>::load(file.path(), cache).unwrap();
+        for (i, &ident) in (0..).zip(&idents) {
+            let node = store.read_node(ident).unwrap();
+            let Node::Leaf(leaf) = &*node else {
+                panic!("node {ident} is not a leaf");
+            };
+            assert!(matches!(leaf.values[..], [LeafValue::Inline(value)] if value == i + 100));
+        }
+    }
+
+    #[test]
+    fn reads_never_cause_writes() {
+        let file = TempFile::new("clean-reads");
+        let mut tree = Tree::<u32, Vec<u8>>::new(file.path(), 128, CacheConfig::default()).unwrap();
+        for key in 0..500 {
+            // every tenth value is moved to overflow blocks
+            let len = if key % 10 == 0 { 300 } else { 8 };
+            tree.insert(key, vec![key as u8; len]).unwrap();
+        }
+        tree.save().unwrap();
+        drop(tree);
+
+        // a tiny cache pages nodes out all the time
+        let cache = CacheConfig {
+            capacity: CacheCapacity::Nodes(2),
+            ..CacheConfig::default()
+        };
+        let (store, root) = FileStore::<u32, Vec<u8>>::load(file.path(), cache).unwrap();
+        let store = Rc::new(RefCell::new(store));
+        let shared: SharedNodeStore<u32, Vec<u8>> = store.clone();
+
+        for key in 0..500 {
+            let value = Node::search(root, &key, shared.clone()).unwrap().unwrap();
+            assert_eq!(value[0], key as u8);
+        }
+        let entries = Range::new(&*shared, root, Bound::Unbounded, Bound::Unbounded).count();
+        assert_eq!(entries, 500);
+
+        let mut store = store.borrow_mut();
+        assert_eq!(store.cache.dirty_nodes().count(), 0);
+        assert!(store.pending.is_empty());
+
+        // the next commit only has the metadata to write
+        let metadata = Metadata::from_bytes(&store.get_block(0).unwrap());
+        store.set_metadata(metadata).unwrap();
+        let staged: Vec<usize> = store.pending.keys().copied().collect();
+        assert_eq!(staged, [0]);
+        store.commit().unwrap();
+    }
+
+    #[test]
+    fn rollbacks_without_changes_keep_the_cache() {
+        let file = TempFile::new("rollback-cache");
+        let mut tree = Tree::<u32, u32>::new(file.path(), 128, CacheConfig::default()).unwrap();
+        for key in 0..200 {
+            tree.insert(key, key).unwrap();
+        }
+        tree.save().unwrap();
+        drop(tree);
+
+        let (mut store, root) =
+            FileStore::<u32, u32>::load(file.path(), CacheConfig::default()).unwrap();
+        let leaf = store.read_inner(root).unwrap().children[0];
+        store.read_node(leaf).unwrap();
+        assert_eq!(store.cache.len(), 2);
+
+        store.rollback();
+        assert_eq!(store.cache.len(), 2);
+
+        // a node handed out to be changed may hold uncommitted changes
+        store.get_node(leaf).unwrap();
+        store.rollback();
+        assert!(store.cache.is_empty());
+    }
+
+    #[test]
+    fn load_replays_the_log() {
+        let file = TempFile::new("replay");
+        let mut tree = Tree::<u32, u32>::new(file.path(), 128, CacheConfig::default()).unwrap();
+        for key in 0..200 {
+            tree.insert(key, key).unwrap();
+        }
+        tree.save().unwrap();
+        let saved = fs::read(file.path()).unwrap();
+
+        // few enough commits that none of them is checkpointed
+        for key in 0..40 {
+            tree.remove(&key).unwrap();
+        }
+        for key in 200..260 {
+            tree.upsert(key, key * 2).unwrap();
+        }
+        drop(tree);
+        let log_size = fs::metadata(Wal::path(&file.path())).unwrap().len();
+        assert!(log_size > 0 && log_size < CHECKPOINT_BLOCKS * 128);
+
+        // a crash loses the blocks written in place since the last checkpoint, and part of a
+        // commit that was being appended to the log
+        fs::write(file.path(), saved).unwrap();
+        let mut wal = OpenOptions::new()
+            .append(true)
+            .open(Wal::path(&file.path()))
+            .unwrap();
+        wal.write_all(&[1, 5, 0, 0, 0, 0, 0, 0, 0, 128, 0, 0, 0, 42])
+            .unwrap();
+        drop(wal);
+
+        let tree = Tree::<u32, u32>::load(file.path(), CacheConfig::default()).unwrap();
+        assert!(tree.verify().unwrap().is_ok());
+        let entries: Vec<(u32, u32)> = tree.iter().collect::<Result<_, _>>().unwrap();
+        let expected: Vec<(u32, u32)> = (40..200)
+            .map(|key| (key, key))
+            .chain((200..260).map(|key| (key, key * 2)))
+            .collect();
+        assert_eq!(entries, expected);
+        assert_eq!(fs::metadata(Wal::path(&file.path())).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn files_of_other_formats_are_rejected() {
+        let file = TempFile::new("old-format");
+        // the metadata of the first versions: fanout, block size, ident and key size, node counter
+        // and root, followed by nodes of fixed-size arrays
+        let mut block = vec![0; 128];
+        for (i, param) in [10usize, 128, 4, 8].into_iter().enumerate() {
+            block[i * 8..(i + 1) * 8].copy_from_slice(&param.to_le_bytes());
+        }
+        block[32..36].copy_from_slice(&1i32.to_le_bytes());
+        block[36..40].copy_from_slice(&1i32.to_le_bytes());
+        block.extend_from_slice(&[0; 128]);
+        fs::write(file.path(), block).unwrap();
+
+        assert!(matches!(
+            FileStore::<i64, i64>::load(file.path(), CacheConfig::default()),
+            Err(LoadError::UnsupportedFormat)
+        ));
+        assert!(matches!(
+            Tree::<i64, i64>::load(file.path(), CacheConfig::default()),
+            Err(TreeError::LoadError(LoadError::UnsupportedFormat))
+        ));
+    }
+
+    #[test]
+    fn shadow_paged_files_ignore_stray_logs() {
+        let file = TempFile::new("stray-log");
+        let mut tree = Tree::<u32, u32>::with_durability(
+            file.path(),
+            128,
+            Durability::ShadowPaging,
+            CacheConfig::default(),
+        )
+        .unwrap();
+        for key in 0..200 {
+            tree.insert(key, key).unwrap();
+        }
+        drop(tree);
+
+        // a committed log of some other file, overwriting blocks the tree uses
+        let mut wal = Wal::open(&file.path()).unwrap();
+        let blocks = (1..20).map(|index| (index, vec![0xff; 128])).collect();
+        wal.append(&blocks).unwrap();
+        drop(wal);
+
+        let tree = Tree::<u32, u32>::load(file.path(), CacheConfig::default()).unwrap();
+        let report = tree.verify().unwrap();
+        assert!(report.is_ok(), "{report}");
+        let keys: Vec<u32> = tree.iter().map(|entry| entry.unwrap().0).collect();
+        assert_eq!(keys, (0..200).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn snapshots_keep_one_version_per_block() {
+        let file = TempFile::new("versions");
+        let mut store = FileStore::<u32, u32>::new(
+            file.path(),
+            128,
+            Durability::WriteAheadLog,
+            CacheConfig::default(),
+        )
+        .unwrap();
+        let payload = payload_size(128);
+        let block = |byte| vec![byte; 128];
+
+        let index = store.allocate_block().unwrap() as usize;
+        store.set_block(index, block(1)).unwrap();
+        store.set_metadata(metadata(&store)).unwrap();
+        store.commit().unwrap();
+
+        let (first, _) = store.snapshot().unwrap();
+        for byte in 2..10 {
+            store.set_block(index, block(byte)).unwrap();
+            store.commit().unwrap();
+        }
+        let (second, _) = store.snapshot().unwrap();
+        for byte in 10..20 {
+            store.set_block(index, block(byte)).unwrap();
+            store.commit().unwrap();
+        }
+
+        // only the first overwrite after each snapshot is kept
+        assert_eq!(store.versions[&index].len(), 2);
+        let read = |store: &mut FileStore<u32, u32>, snapshot| {
+            store.snapshot_block(snapshot, index).unwrap()[..payload].to_vec()
+        };
+        assert_eq!(read(&mut store, first), block(1)[..payload]);
+        assert_eq!(read(&mut store, second), block(9)[..payload]);
+        assert_eq!(
+            store.get_block(index).unwrap()[..payload],
+            block(19)[..payload]
+        );
+
+        store.release_snapshot(first);
+        assert_eq!(store.versions[&index].len(), 1);
+        assert_eq!(read(&mut store, second), block(9)[..payload]);
+        store.release_snapshot(second);
+        assert!(store.versions.is_empty());
+    }
+
+    #[test]
+    fn failed_reads_are_told_apart_from_invalid_references() {
+        let file = TempFile::new("failed_reads");
+        let mut tree = Tree::<u32, u32>::new(file.path(), 128, CacheConfig::default()).unwrap();
+        for key in 0..200 {
+            tree.insert(key, key).unwrap();
+        }
+        tree.save().unwrap();
+        drop(tree);
+
+        let (store, _) = FileStore::<u32, u32>::load(file.path(), CacheConfig::default()).unwrap();
+        let last = store.node_ctr() as usize;
+        assert!(store.get_block(last).is_ok());
+        assert!(matches!(
+            store.get_block(last + 1),
+            Err(NodeStoreError::InvalidReference)
+        ));
+
+        // the block exists, but the file ends before it
+        store.file.set_len((128 * last) as u64).unwrap();
+        assert!(matches!(
+            store.get_block(last),
+            Err(NodeStoreError::ReadFailed)
+        ));
+    }
+
+    #[test]
+    fn checksums_keep_blocks_aligned() {
+        let file = TempFile::new("aligned");
+        let mut tree = Tree::<u32, u32>::new(file.path(), 4096, CacheConfig::default()).unwrap();
+        for key in 0..2000 {
+            tree.insert(key, key).unwrap();
+        }
+        tree.save().unwrap();
+        drop(tree);
+
+        let len = fs::metadata(file.path()).unwrap().len();
+        assert_eq!(len % 4096, 0);
+
+        // the first leaf still holds the smallest keys
+        let file_handle = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(file.path())
+            .unwrap();
+        let mut byte = [0];
+        file_handle.read_exact_at(&mut byte, 4096 + 100).unwrap();
+        file_handle.write_all_at(&[!byte[0]], 4096 + 100).unwrap();
+
+        let tree = Tree::<u32, u32>::load(file.path(), CacheConfig::default()).unwrap();
+        assert!(matches!(
+            tree.search(&0),
+            Err(TreeError::Corrupted { block: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn corrupted_shadow_pages_are_reported_by_index() {
+        let file = TempFile::new("corrupted_shadow");
+        let mut tree = Tree::<u32, u32>::with_durability(
+            file.path(),
+            128,
+            Durability::ShadowPaging,
+            CacheConfig::default(),
+        )
+        .unwrap();
+        for key in 0..200 {
+            tree.insert(key, key).unwrap();
+        }
+        tree.save().unwrap();
+        for key in 0..200 {
+            tree.upsert(key, key + 1).unwrap();
+        }
+        drop(tree);
+
+        // a leaf that was moved away from the place matching its index
+        let (store, _) = FileStore::<u32, u32>::load(file.path(), CacheConfig::default()).unwrap();
+        let places: Vec<(usize, usize)> = match &store.journal {
+            Journal::Shadow(table) => (1..=store.node_ctr() as usize)
+                .map(|index| (index, table.get(index).unwrap()))
+                .collect(),
+            Journal::Wal(_) => panic!("the tree is not shadow paged"),
+        };
+        let first_key = |index: usize| {
+            let block = store.get_block(index)?;
+            match Node::<u32, u32>::from_bytes(index as NodeIdent, &block)? {
+                Node::Leaf(leaf) => Ok(leaf.keys[0]),
+                Node::Inner(_) => Err(NodeStoreError::InvalidReference),
+            }
+        };
+        let (index, place, key) = places
+            .into_iter()
+            .filter(|&(index, place)| index != place)
+            .find_map(|(index, place)| Some((index, place, first_key(index).ok()?)))
+            .unwrap();
+        drop(store);
+
+        let file_handle = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(file.path())
+            .unwrap();
+        let mut byte = [0];
+        file_handle
+            .read_exact_at(&mut byte, (place * 128 + 10) as u64)
+            .unwrap();
+        file_handle
+            .write_all_at(&[!byte[0]], (place * 128 + 10) as u64)
+            .unwrap();
+
+        let block = index as NodeIdent;
+        let tree = Tree::<u32, u32>::load(file.path(), CacheConfig::default()).unwrap();
+        assert!(matches!(
+            tree.search(&key),
+            Err(TreeError::Corrupted { block: b, .. }) if b == block
+        ));
+        let report = tree.verify().unwrap();
+        assert!(
+            report.violations.iter().any(|violation| matches!(
+                violation,
+                Violation::UnreadableBlock {
+                    block: b,
+                    error: NodeStoreError::Corrupted { block: e, .. },
+                } if *b == block && *e == block
+            )),
+            "{report}"
+        );
+    }
 }
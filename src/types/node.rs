@@ -1,24 +1,65 @@
-use crate::types::node_store::{NodeStore, NodeStoreError, SharedNodeStore};
-use std::{cell::RefCell, cmp, fmt::Debug, marker::PhantomData, rc::Rc};
+use crate::types::{
+    cache::PageGuard,
+    file_store::{inner_cell_size, leaf_cell_size, max_cell_size, OVERFLOW_REF_SIZE},
+    node_store::{Encode, NodeStore, NodeStoreError, SharedNodeStore},
+};
+use std::{fmt::Debug, mem};
 
 pub type NodeIdent = i32;
-pub type SearchKey = i64;
 
-// We use generics here because rust normally doesn't allow usage of variable length arrays in structs
-// the phantom field will be removed during compilation, it's just here so we can correctly infer
-// leaf types
-// https://doc.rust-lang.org/nomicon/phantom-data.html
+/// Types that can be used as the keys of a tree, e.g. integers, `(u32, u64)` pairs, `[u8; 16]`
+/// UUIDs or strings
+pub trait SearchKey: Ord + Clone + Debug + Encode {}
+
+impl<K> SearchKey for K where K: Ord + Clone + Debug + Encode {}
+
+/// Types that can be stored in the leaves of a tree, e.g. `(u64, u32)` offset/length pairs or blobs
+pub trait Value: Clone + Debug + Encode {}
+
+impl<T> Value for T where T: Clone + Debug + Encode {}
+
+/// A value as it is stored in a leaf. Values that would make their entry too large to share a
+/// block with other entries are moved into a chain of overflow blocks.
+#[derive(Debug, Clone)]
+pub enum LeafValue<T> {
+    Inline(T),
+    Overflow { first: NodeIdent, len: usize },
+}
+
+/// A node of the tree as it is held by the node store. Which of the two it is, is stored in the
+/// first byte of its block.
+#[derive(Debug)]
+pub enum Node<K, T>
+where
+    K: SearchKey,
+    T: Value,
+{
+    Leaf(LeafNode<K, T>),
+    Inner(InnerNode<K>),
+}
+
 #[derive(Debug)]
-pub struct Node<T, const S: usize>
+pub struct LeafNode<K, T>
 where
-    T: Sized,
-    T: Debug,
+    K: SearchKey,
+    T: Value,
 {
-    // separators is only populated up to S-1
-    pub separators: [SearchKey; S],
-    pub children: [NodeIdent; S],
-    pub size: usize,
-    pub phantom: PhantomData<T>,
+    pub keys: Vec<K>,
+    // stored values, one for each key
+    pub values: Vec<LeafValue<T>>,
+    // neighbouring leaves, 0 if there is none
+    pub next: NodeIdent,
+    pub prev: NodeIdent,
+}
+
+#[derive(Debug)]
+pub struct InnerNode<K>
+where
+    K: SearchKey,
+{
+    pub separators: Vec<K>,
+    // subtrees, one more than there are separators
+    pub children: Vec<NodeIdent>,
 }
 
 pub struct Data<T: Sized> {
@@ -30,326 +71,759 @@ pub enum NodeCreationError {
 }
 
 #[derive(Debug)]
-pub enum InsertionResult<T, const S: usize> {
-    Ok,
-    NodeOverflow(SearchKey, NodeIdent, PhantomData<T>),
-    Error(String),
+pub enum InsertionResult<K, T> {
+    /// the entry was inserted, holds the value it replaced if there was one
+    Ok(Option<T>),
+    NodeOverflow(Option<T>, K, NodeIdent),
+    /// the value of an existing entry was replaced with a smaller one, and the node now holds less
+    /// than the minimum amount of entries and has to be fixed up by its parent
+    NodeUnderflow(Option<T>),
     InsertError(NodeStoreError),
     DuplicateKey,
+    /// the key does not fit into a node, even if its value is moved to overflow blocks
+    KeyTooLarge,
+}
+
+#[derive(Debug)]
+pub enum RemovalResult<K, T> {
+    Ok(T),
+    /// the key was removed, but the node now holds less than the minimum amount of entries and
+    /// has to be fixed up by its parent
+    NodeUnderflow(T),
+    /// the key was removed, but moving entries between the children replaced a separator with a
+    /// larger one and the node had to be split
+    NodeOverflow(T, K, NodeIdent),
+    NotFound,
+    RemoveError(NodeStoreError),
+}
+
+/// what became of a node after one of its children was rebalanced
+enum Rebalanced<K> {
+    Fits,
+    Underflow,
+    /// moving entries between the children replaced a separator with a larger one and the node
+    /// had to be split, holds the separator and identifier of the new right node
+    Overflow(K, NodeIdent),
 }
 
-/// inserts the given key into the array, moving all following elements accordingly
-fn insert_into_array<T>(slice: &mut [T], index: usize, key: T, empty: T) -> Option<T>
+/// index of the subtree that may contain the given key. Separators are the smallest key of their
+/// right subtree, so equal keys have to continue to the right.
+fn subtree_index<K: SearchKey>(separators: &[K], key: &K) -> usize {
+    separators.partition_point(|sep| sep <= key)
+}
+
+/// index of the last entry staying in the left node when splitting, chosen so both halves end up
+/// with about the same amount of bytes
+fn split_index(cell_sizes: &[usize]) -> usize {
+    let total: usize = cell_sizes.iter().sum();
+    let mut prefix = 0;
+
+    let index = cell_sizes
+        .iter()
+        .position(|size| {
+            prefix += size;
+            prefix * 2 >= total
+        })
+        .unwrap_or(0);
+
+    // both halves need at least one entry, inner nodes also lose the middle one to the parent
+    index.clamp(1, cell_sizes.len() - 2)
+}
+
+impl<T> LeafValue<T>
 where
-    T: cmp::PartialEq,
-    T: Copy,
-    T: Debug,
+    T: Value,
 {
-    let len = slice.len();
-    let mut hanging = key;
+    /// prepares the value for being stored next to the key, moving it to overflow blocks if the
+    /// entry would be too large otherwise. Returns `None` if the key itself is too large.
+    pub fn store<K: SearchKey>(
+        key: &K,
+        data: T,
+        node_store: &mut dyn NodeStore<K, T>,
+    ) -> Result<Option<Self>, NodeStoreError> {
+        match LeafValue::stays_inline(key, &data, node_store.block_size()) {
+            Some(true) => return Ok(Some(LeafValue::Inline(data))),
+            Some(false) => {}
+            None => return Ok(None),
+        }
 
-    // continue swapping keys until end of the slice is reached.
-    for i in index..len {
-        let tmp = slice[i];
-        slice[i] = hanging;
+        let bytes = data.to_vec();
+        let first = node_store.store_overflow(&bytes)?;
 
-        // return early if we read a null value, since that means the array wasn't full
-        if tmp == empty {
-            return None;
+        Ok(Some(LeafValue::Overflow {
+            first,
+            len: bytes.len(),
+        }))
+    }
+
+    /// whether `store` keeps the value inside of the leaf instead of moving it to overflow blocks,
+    /// `None` if the key is too large to be stored either way
+    pub fn stays_inline<K: SearchKey>(key: &K, data: &T, block_size: usize) -> Option<bool> {
+        let max_cell_size = max_cell_size(block_size);
+        if leaf_cell_size(key.encoded_size(), data.encoded_size()) <= max_cell_size {
+            Some(true)
+        } else if leaf_cell_size(key.encoded_size(), OVERFLOW_REF_SIZE) <= max_cell_size {
+            Some(false)
+        } else {
+            None
+        }
+    }
+
+    /// returns the stored value, reading it from its overflow blocks if necessary
+    pub fn resolve<K: SearchKey>(
+        &self,
+        node_store: &mut dyn NodeStore<K, T>,
+    ) -> Result<T, NodeStoreError> {
+        match self {
+            LeafValue::Inline(value) => Ok(value.clone()),
+            LeafValue::Overflow { first, len } => {
+                T::decode(&node_store.load_overflow(*first, *len)?).ok_or_else(|| {
+                    NodeStoreError::Malformed {
+                        block: *first,
+                        reason: "the overflow chain holds no valid value".to_string(),
+                    }
+                })
+            }
+        }
+    }
+
+    /// amount of bytes the value takes up inside of its leaf
+    pub fn stored_size(&self) -> usize {
+        match self {
+            LeafValue::Inline(value) => value.encoded_size(),
+            LeafValue::Overflow { .. } => OVERFLOW_REF_SIZE,
+        }
+    }
+}
+
+impl<K, T> LeafNode<K, T>
+where
+    K: SearchKey,
+    T: Value,
+{
+    pub fn new() -> Self {
+        LeafNode {
+            keys: vec![],
+            values: vec![],
+            next: 0,
+            prev: 0,
         }
+    }
 
-        hanging = tmp;
+    /// amount of bytes each entry takes up inside of the leaf's block
+    pub fn cell_sizes(&self) -> Vec<usize> {
+        self.keys
+            .iter()
+            .zip(&self.values)
+            .map(|(key, value)| leaf_cell_size(key.encoded_size(), value.stored_size()))
+            .collect()
     }
 
-    Some(hanging)
+    /// moves the upper half of the entries into a new leaf. The smallest key of the new leaf
+    /// becomes the separator in the parent.
+    fn split(&mut self) -> (K, LeafNode<K, T>) {
+        let split_at = split_index(&self.cell_sizes());
+
+        let right_keys = self.keys.split_off(split_at + 1);
+        let right_values = self.values.split_off(split_at + 1);
+        let root_sep = right_keys[0].clone();
+
+        let right_node = LeafNode {
+            keys: right_keys,
+            values: right_values,
+            next: 0,
+            prev: 0,
+        };
+
+        (root_sep, right_node)
+    }
+
+    fn to_graphviz(&self, node_id: &NodeIdent) -> String {
+        let mut result = format!("{} [shape=record, label=\"", node_id);
+        let labels: Vec<String> = self
+            .keys
+            .iter()
+            .map(|key| format!("{{ {} }}", escape_label(key)))
+            .collect();
+        result.push_str(&labels.join(" | "));
+        result.push_str("\"];");
+
+        if self.next != 0 {
+            result.push_str(&format!(
+                "\n{} -> {} [style=dashed, constraint=false];",
+                node_id, self.next
+            ));
+        }
+
+        result
+    }
 }
 
-impl<T, const S: usize> Node<T, S>
+impl<K, T> Default for LeafNode<K, T>
 where
-    T: Sized,
-    T: Debug,
+    K: SearchKey,
+    T: Value,
 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K> InnerNode<K>
+where
+    K: SearchKey,
+{
+    pub fn new() -> Self {
+        InnerNode {
+            separators: vec![],
+            children: vec![],
+        }
+    }
+
+    /// amount of bytes each entry takes up inside of the node's block
+    pub fn cell_sizes(&self) -> Vec<usize> {
+        self.separators
+            .iter()
+            .map(|key| inner_cell_size(key.encoded_size()))
+            .collect()
+    }
+
+    /// moves the upper half of the entries into a new node. The separator in the middle moves up
+    /// into the parent.
+    fn split(&mut self) -> (K, InnerNode<K>) {
+        let split_at = split_index(&self.cell_sizes());
+
+        let right_seps = self.separators.split_off(split_at + 1);
+        let right_children = self.children.split_off(split_at + 1);
+        let root_sep = self.separators.pop().unwrap();
+
+        let right_node = InnerNode {
+            separators: right_seps,
+            children: right_children,
+        };
+
+        (root_sep, right_node)
+    }
+
+    fn to_graphviz(&self, node_id: &NodeIdent) -> String {
+        let mut result = format!("{} [shape=record,label=\"<sep0> ", node_id);
+        for (i, key) in self.separators.iter().enumerate() {
+            result.push_str(&format!("| {} | <sep{}> ", escape_label(key), i + 1));
+        }
+        result.push_str("\"];");
+
+        for (i, child) in self.children.iter().enumerate() {
+            result.push_str(&format!("\n{}:sep{} -> {};", node_id, i, child));
+        }
+
+        result
+    }
+}
+
+impl<K> Default for InnerNode<K>
+where
+    K: SearchKey,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, T> Node<K, T>
+where
+    K: SearchKey,
+    T: Value,
+{
+    pub fn is_leaf(&self) -> bool {
+        matches!(self, Node::Leaf(_))
+    }
+
+    /// inserts the entry into the subtree. Existing entries with the same key are only replaced
+    /// if `replace` is set, otherwise the insertion fails.
     pub fn insert(
         self_id: NodeIdent,
-        key: SearchKey,
-        data: NodeIdent,
-        shared_node_store: SharedNodeStore<T, S>,
-    ) -> InsertionResult<T, S> {
-        if self_id < 0 {
-            Node::insert_inner(self_id, key, data, shared_node_store)
+        key: K,
+        data: T,
+        replace: bool,
+        shared_node_store: SharedNodeStore<K, T>,
+    ) -> InsertionResult<K, T> {
+        let is_leaf = match shared_node_store.borrow_mut().read_node(self_id) {
+            Ok(n) => n.is_leaf(),
+            Err(e) => return InsertionResult::InsertError(e),
+        };
+
+        if is_leaf {
+            Node::insert_leaf(self_id, key, data, replace, shared_node_store)
         } else {
-            Node::insert_leaf(self_id, key, data, shared_node_store)
+            Node::insert_inner(self_id, key, data, replace, shared_node_store)
         }
     }
 
     pub fn search(
         self_id: NodeIdent,
-        key: SearchKey,
-        shared_node_store: SharedNodeStore<T, S>,
-    ) -> Result<Option<NodeIdent>, NodeStoreError> {
+        key: &K,
+        shared_node_store: SharedNodeStore<K, T>,
+    ) -> Result<Option<T>, NodeStoreError> {
         let child = {
             let mut node_store = shared_node_store.borrow_mut();
-            let node = node_store.get_node(self_id)?;
-
-            let separators = &node.separators[0..node.size];
-
-            let subtree_index = match separators.binary_search(&key) {
-                Ok(r) => {
-                    if self_id < 0 {
-                        r
-                    } else {
-                        return Ok(Some(node.children[r]));
-                    }
-                }
-                Err(r) => {
-                    if self_id < 0 {
-                        r
-                    } else {
-                        return Ok(None);
-                    }
+            // the leaf stays pinned while its value is read from overflow blocks
+            let current_node = node_store.pin_node(self_id)?;
+            let node = current_node.node();
+
+            match &*node {
+                Node::Leaf(leaf) => {
+                    let value = match leaf.keys.binary_search(key) {
+                        Ok(r) => leaf.values[r].clone(),
+                        Err(_) => return Ok(None),
+                    };
+
+                    return value.resolve(&mut *node_store).map(Some);
                 }
-            };
-
-            node.children[subtree_index]
+                Node::Inner(inner) => inner.children[subtree_index(&inner.separators, key)],
+            }
         };
 
-        return Node::search(child, key, shared_node_store);
+        Node::search(child, key, shared_node_store)
     }
 
     fn insert_inner(
         self_id: NodeIdent,
-        key: SearchKey,
-        data: NodeIdent,
-        shared_node_store: SharedNodeStore<T, S>,
-    ) -> InsertionResult<T, S> {
-        let (insert_child, insertion_index) = {
+        key: K,
+        data: T,
+        replace: bool,
+        shared_node_store: SharedNodeStore<K, T>,
+    ) -> InsertionResult<K, T> {
+        let (current_node, insert_child, insertion_index) = {
             let mut node_store = shared_node_store.borrow_mut();
-            let current_node = match node_store.get_node(self_id) {
+            // the node stays pinned while the subtree below it changes, so it is still cached
+            // once it has to take in the separator of an overflowing child
+            let current_node = match node_store.pin_node(self_id) {
                 Ok(n) => n,
                 Err(e) => return InsertionResult::InsertError(e),
             };
 
-            let insertion_index =
-                match &current_node.separators[0..current_node.size].binary_search(&key) {
-                    Ok(_) => return InsertionResult::DuplicateKey,
-                    Err(u) => u,
+            let (insert_child, insertion_index) = match current_node.inner() {
+                Ok(inner) => {
+                    // duplicates are detected by the leaf, separators may outlive the key they were copied from
+                    let insertion_index = subtree_index(&inner.separators, &key);
+                    (inner.children[insertion_index], insertion_index)
                 }
-                .clone();
+                Err(e) => return InsertionResult::InsertError(e),
+            };
 
-            (current_node.children[insertion_index], insertion_index)
+            (current_node, insert_child, insertion_index)
         };
 
         // we explicitly drop our node_store by exiting the scope so the child node can open the refcell without panic
-        let res = Node::insert(insert_child, key, data, shared_node_store.clone());
+        let res = Node::insert(insert_child, key, data, replace, shared_node_store.clone());
 
-        let (new_sep, new_node_ident) = match res {
-            InsertionResult::NodeOverflow(new_sep, new_node_ident, _phantom) => {
-                (new_sep, new_node_ident)
+        let (old, new_sep, new_node_ident) = match res {
+            InsertionResult::NodeOverflow(old, new_sep, new_node_ident) => {
+                (old, new_sep, new_node_ident)
+            }
+            InsertionResult::NodeUnderflow(old) => {
+                let mut node_store = shared_node_store.borrow_mut();
+                return match Node::fix_up_child(&current_node, insertion_index, &mut *node_store) {
+                    Ok(Rebalanced::Fits) => InsertionResult::Ok(old),
+                    Ok(Rebalanced::Underflow) => InsertionResult::NodeUnderflow(old),
+                    Ok(Rebalanced::Overflow(root_sep, ident)) => {
+                        InsertionResult::NodeOverflow(old, root_sep, ident)
+                    }
+                    Err(e) => InsertionResult::InsertError(e),
+                };
             }
             r => return r,
         };
 
-        // we need to fix up the current node, but we dropped our previous reference.
         let mut node_store = shared_node_store.borrow_mut();
-        let current_node = match node_store.get_node(self_id) {
+        match Node::insert_separator(
+            &current_node,
+            insertion_index,
+            new_sep,
+            new_node_ident,
+            &mut *node_store,
+        ) {
+            Ok(Some((root_sep, right_node_ident))) => {
+                InsertionResult::NodeOverflow(old, root_sep, right_node_ident)
+            }
+            Ok(None) => InsertionResult::Ok(old),
+            Err(e) => InsertionResult::InsertError(e),
+        }
+    }
+
+    /// inserts the separator and new right subtree of an overflowing child. Returns the separator
+    /// and identifier of the new right node if this node had to be split as well.
+    fn insert_separator(
+        node: &PageGuard<K, T>,
+        child_index: usize,
+        separator: K,
+        right_child: NodeIdent,
+        node_store: &mut dyn NodeStore<K, T>,
+    ) -> Result<Option<(K, NodeIdent)>, NodeStoreError> {
+        let block_size = node_store.block_size();
+        let mut current_node = node.inner_mut()?;
+
+        // our returned node is a right subtree to ident, so it has to be inserted one to the right
+        current_node.separators.insert(child_index, separator);
+        current_node.children.insert(child_index + 1, right_child);
+
+        if current_node.fits(block_size) {
+            return Ok(None);
+        }
+
+        let (root_sep, right_node) = current_node.split();
+        let right_node_ident = node_store.store_node(Node::Inner(right_node))?;
+
+        Ok(Some((root_sep, right_node_ident)))
+    }
+
+    fn insert_leaf(
+        self_id: NodeIdent,
+        key: K,
+        data: T,
+        replace: bool,
+        shared_node_store: SharedNodeStore<K, T>,
+    ) -> InsertionResult<K, T> {
+        let mut node_store = shared_node_store.borrow_mut();
+        let block_size = node_store.block_size();
+        // the leaf stays pinned while values are moved to overflow blocks and a split off node is
+        // stored
+        let leaf = match node_store.pin_node(self_id) {
             Ok(n) => n,
             Err(e) => return InsertionResult::InsertError(e),
         };
 
-        // Since we access the separators on a smaller slice, the two will overflow at the same time
-        let overflow_key = insert_into_array::<SearchKey>(
-            &mut current_node.separators[0..S - 1],
-            insertion_index,
-            new_sep,
-            0,
-        );
+        // the leaf stays unchanged if the key is already present
+        let (insertion_index, exists) = match leaf.leaf() {
+            Ok(n) => match n.keys.binary_search(&key) {
+                Ok(_u) if !replace => return InsertionResult::DuplicateKey,
+                Ok(u) => (u, true),
+                Err(u) => (u, false),
+            },
+            Err(e) => return InsertionResult::InsertError(e),
+        };
 
-        // our returned node is a right subtree to ident, so it has to be inserted one to the right
-        let overflow_value = insert_into_array::<NodeIdent>(
-            &mut current_node.children[0..S],
-            insertion_index + 1,
-            new_node_ident,
-            0,
-        );
-
-        current_node.size += 1;
-
-        let (key, value) = match (overflow_key, overflow_value) {
-            (None, None) => return InsertionResult::Ok,
-            (Some(key), Some(value)) => (key, value),
-            (key, value) => {
-                return InsertionResult::Error(format!(
-                    "Mismatched overflow: key was {key:?}, value was {value:?}"
-                ))
-            }
+        let value = match LeafValue::store(&key, data, &mut *node_store) {
+            Ok(Some(value)) => value,
+            Ok(None) => return InsertionResult::KeyTooLarge,
+            Err(e) => return InsertionResult::InsertError(e),
+        };
+
+        let mut current_node = match leaf.leaf_mut() {
+            Ok(n) => n,
+            Err(e) => return InsertionResult::InsertError(e),
         };
 
-        let (root_sep, right_seps, right_children) = current_node.split_inner(key, value);
+        let replaced = if exists {
+            Some(mem::replace(
+                &mut current_node.values[insertion_index],
+                value,
+            ))
+        } else {
+            current_node.keys.insert(insertion_index, key);
+            current_node.values.insert(insertion_index, value);
+            None
+        };
 
-        let right_node = Node {
-            children: right_children,
-            separators: right_seps,
-            size: S / 2,
-            phantom: PhantomData::<T>,
+        let old = match replaced.map(|stored| Node::release_value(stored, &mut *node_store)) {
+            Some(Ok(v)) => Some(v),
+            Some(Err(e)) => return InsertionResult::InsertError(e),
+            None => None,
         };
 
-        let right_node_ident = match node_store.store_node(right_node, false) {
+        // a replaced value may be larger or smaller than the old one, so the leaf can overflow
+        // either way, or even underflow
+        if current_node.fits(block_size) {
+            return if current_node.underflows(block_size) {
+                InsertionResult::NodeUnderflow(old)
+            } else {
+                InsertionResult::Ok(old)
+            };
+        }
+
+        let (root_sep, mut right_node) = current_node.split();
+        let old_next = current_node.next;
+
+        // the new node goes in between the current leaf and its old neighbour
+        right_node.next = old_next;
+        right_node.prev = self_id;
+
+        let right_node_ident = match node_store.store_node(Node::Leaf(right_node)) {
             Ok(i) => i,
-            Err(_) => todo!(),
+            Err(e) => return InsertionResult::InsertError(e),
         };
+        current_node.next = right_node_ident;
+
+        if old_next != 0 {
+            match node_store.get_leaf(old_next) {
+                Ok(mut n) => n.prev = right_node_ident,
+                Err(e) => return InsertionResult::InsertError(e),
+            };
+        }
 
-        InsertionResult::NodeOverflow(root_sep, right_node_ident, PhantomData::<T>)
+        InsertionResult::NodeOverflow(old, root_sep, right_node_ident)
     }
 
-    fn split_inner(
-        &mut self,
-        largest_key: SearchKey,
-        largest_value: NodeIdent,
-    ) -> (SearchKey, [SearchKey; S], [NodeIdent; S]) {
-        let target_size = S / 2;
+    fn release_value(
+        stored: LeafValue<T>,
+        node_store: &mut dyn NodeStore<K, T>,
+    ) -> Result<T, NodeStoreError> {
+        let value = stored.resolve(node_store)?;
 
-        // Seps is actually populated to size S-1, which means the right slice has a size of target_size
-        let right_seps_slice = &mut self.separators[target_size - 1..S - 1];
-        let right_children_slice = &mut self.children[target_size..S];
+        // nothing references the overflow blocks anymore, so they can be reused
+        if let LeafValue::Overflow { first, .. } = stored {
+            node_store.free_overflow(first)?;
+        }
 
-        let mut right_seps = [0; S];
-        let mut right_children = [0; S];
+        Ok(value)
+    }
+
+    pub fn remove(
+        self_id: NodeIdent,
+        key: &K,
+        shared_node_store: SharedNodeStore<K, T>,
+    ) -> RemovalResult<K, T> {
+        let is_leaf = match shared_node_store.borrow_mut().read_node(self_id) {
+            Ok(n) => n.is_leaf(),
+            Err(e) => return RemovalResult::RemoveError(e),
+        };
 
-        let root_sep = right_seps_slice[0];
+        if is_leaf {
+            Node::remove_leaf(self_id, key, shared_node_store)
+        } else {
+            Node::remove_inner(self_id, key, shared_node_store)
+        }
+    }
+
+    fn remove_inner(
+        self_id: NodeIdent,
+        key: &K,
+        shared_node_store: SharedNodeStore<K, T>,
+    ) -> RemovalResult<K, T> {
+        let (current_node, remove_child, child_index) = {
+            let mut node_store = shared_node_store.borrow_mut();
+            // the node stays pinned while the subtree below it changes, so it is still cached
+            // once it has to fix up its child
+            let current_node = match node_store.pin_node(self_id) {
+                Ok(n) => n,
+                Err(e) => return RemovalResult::RemoveError(e),
+            };
 
-        // do not copy the first key, since that is the new separator
-        right_seps[0..target_size - 1].copy_from_slice(&right_seps_slice[1..target_size]);
-        right_children[0..target_size].copy_from_slice(right_children_slice);
+            let (remove_child, child_index) = match current_node.inner() {
+                Ok(inner) => {
+                    let child_index = subtree_index(&inner.separators, key);
+                    (inner.children[child_index], child_index)
+                }
+                Err(e) => return RemovalResult::RemoveError(e),
+            };
 
-        // insert elements that did not fit into the original node
-        right_seps[target_size - 1] = largest_key;
-        right_children[target_size] = largest_value;
+            (current_node, remove_child, child_index)
+        };
 
-        // update left node
-        right_seps_slice.fill(0);
-        right_children_slice.fill(0);
-        self.size = target_size - 1;
+        let res = Node::remove(remove_child, key, shared_node_store.clone());
+        let mut node_store = shared_node_store.borrow_mut();
 
-        (root_sep, right_seps, right_children)
+        match res {
+            RemovalResult::NodeUnderflow(value) => {
+                match Node::fix_up_child(&current_node, child_index, &mut *node_store) {
+                    Ok(Rebalanced::Fits) => RemovalResult::Ok(value),
+                    Ok(Rebalanced::Underflow) => RemovalResult::NodeUnderflow(value),
+                    Ok(Rebalanced::Overflow(root_sep, ident)) => {
+                        RemovalResult::NodeOverflow(value, root_sep, ident)
+                    }
+                    Err(e) => RemovalResult::RemoveError(e),
+                }
+            }
+            RemovalResult::NodeOverflow(value, new_sep, new_node_ident) => {
+                match Node::insert_separator(
+                    &current_node,
+                    child_index,
+                    new_sep,
+                    new_node_ident,
+                    &mut *node_store,
+                ) {
+                    Ok(Some((root_sep, right_node_ident))) => {
+                        RemovalResult::NodeOverflow(value, root_sep, right_node_ident)
+                    }
+                    Ok(None) => RemovalResult::Ok(value),
+                    Err(e) => RemovalResult::RemoveError(e),
+                }
+            }
+            r => r,
+        }
     }
 
-    fn insert_leaf(
+    fn remove_leaf(
         self_id: NodeIdent,
-        key: SearchKey,
-        data: NodeIdent,
-        shared_node_store: Rc<RefCell<(dyn NodeStore<T, S>)>>,
-    ) -> InsertionResult<T, S> {
+        key: &K,
+        shared_node_store: SharedNodeStore<K, T>,
+    ) -> RemovalResult<K, T> {
         let mut node_store = shared_node_store.borrow_mut();
-        let current_node = match node_store.get_node(self_id) {
+        let block_size = node_store.block_size();
+        let leaf = match node_store.pin_node(self_id) {
             Ok(n) => n,
+            Err(e) => return RemovalResult::RemoveError(e),
+        };
 
-            Err(e) => return InsertionResult::InsertError(e),
+        let removal_index = match leaf.leaf() {
+            Ok(n) => match n.keys.binary_search(key) {
+                Ok(u) => u,
+                Err(_u) => return RemovalResult::NotFound,
+            },
+            Err(e) => return RemovalResult::RemoveError(e),
         };
 
-        let separators = &current_node.separators[0..current_node.size];
+        let (stored, underflow) = match leaf.leaf_mut() {
+            Ok(mut current_node) => {
+                current_node.keys.remove(removal_index);
+                let stored = current_node.values.remove(removal_index);
+                (stored, current_node.underflows(block_size))
+            }
+            Err(e) => return RemovalResult::RemoveError(e),
+        };
 
-        let insertion_index = match separators.binary_search(&key) {
-            Ok(_u) => return InsertionResult::DuplicateKey,
-            Err(u) => u,
+        let value = match Node::release_value(stored, &mut *node_store) {
+            Ok(v) => v,
+            Err(e) => return RemovalResult::RemoveError(e),
         };
 
-        let overflow_key = insert_into_array::<SearchKey>(
-            &mut current_node.separators[0..S],
-            insertion_index,
-            key,
-            0,
-        );
+        if underflow {
+            RemovalResult::NodeUnderflow(value)
+        } else {
+            RemovalResult::Ok(value)
+        }
+    }
 
-        let overflow_value = insert_into_array::<NodeIdent>(
-            &mut current_node.children[0..S],
-            insertion_index,
-            data,
-            0,
-        );
-
-        current_node.size += 1;
-
-        let (key, value) = match (overflow_key, overflow_value) {
-            (None, None) => return InsertionResult::Ok,
-            (Some(key), Some(value)) => (key, value),
-            (key, value) => {
-                return InsertionResult::Error(format!(
-                    "Mismatched overflow: key was {key:?}, value was {value:?}"
-                ))
-            }
-        };
+    /// rebalances the underflowing child, then checks whether the node itself still fits into its
+    /// block and holds enough entries
+    fn fix_up_child(
+        node: &PageGuard<K, T>,
+        child_index: usize,
+        node_store: &mut dyn NodeStore<K, T>,
+    ) -> Result<Rebalanced<K>, NodeStoreError> {
+        Node::rebalance_child(node, child_index, node_store)?;
+
+        let block_size = node_store.block_size();
+        let mut inner = node.inner_mut()?;
+
+        // moving entries between the children may have replaced a separator with a larger one
+        if !inner.fits(block_size) {
+            let (root_sep, right_node) = inner.split();
+            let ident = node_store.store_node(Node::Inner(right_node))?;
+            return Ok(Rebalanced::Overflow(root_sep, ident));
+        }
 
-        let (root_sep, right_seps, right_children) = current_node.split_leaf(key, value);
+        if inner.underflows(block_size) {
+            Ok(Rebalanced::Underflow)
+        } else {
+            Ok(Rebalanced::Fits)
+        }
+    }
 
-        let right_node = Node {
-            children: right_children,
-            separators: right_seps,
-            size: S / 2 + 1,
-            phantom: PhantomData::<T>,
+    /// fixes up an underflowing child by merging it with one of its siblings, or by moving entries
+    /// over from the sibling if both don't fit into a single node. This is the inverse of splitting.
+    fn rebalance_child(
+        parent: &PageGuard<K, T>,
+        child_index: usize,
+        node_store: &mut dyn NodeStore<K, T>,
+    ) -> Result<(), NodeStoreError> {
+        let block_size = node_store.block_size();
+
+        // prefer the left sibling, the leftmost child has to use its right one
+        let left_index = if child_index > 0 {
+            child_index - 1
+        } else {
+            child_index
         };
 
-        let right_node_ident = match node_store.store_node(right_node, true) {
-            Ok(i) => i,
-            Err(_) => panic!("Unable to store newly created node"),
+        let (left_id, right_id, parent_sep) = {
+            let parent = parent.inner()?;
+            (
+                parent.children[left_index],
+                parent.children[left_index + 1],
+                parent.separators[left_index].clone(),
+            )
         };
 
-        InsertionResult::NodeOverflow(root_sep, right_node_ident, PhantomData::<T>)
-    }
-
-    fn split_leaf(
-        &mut self,
-        largest_key: SearchKey,
-        largest_value: NodeIdent,
-    ) -> (SearchKey, [SearchKey; S], [NodeIdent; S]) {
-        let target_size = S / 2;
+        // both children are pinned along with the parent, so entries move between them directly
+        let left = node_store.pin_node(left_id)?;
+        let right = node_store.pin_node(right_id)?;
 
-        let right_seps_slice = &mut self.separators[target_size..S];
-        let right_children_slice = &mut self.children[target_size..S];
+        let right_next = match &*right.node() {
+            Node::Leaf(leaf) => leaf.next,
+            Node::Inner(_) => 0,
+        };
 
-        let mut right_seps = [0; S];
-        let mut right_children = [0; S];
+        // the separator of the new right node if both don't fit into the left one
+        let new_sep = match (&mut *left.node_mut(), &mut *right.node_mut()) {
+            (Node::Leaf(left), Node::Leaf(right)) => {
+                left.keys.append(&mut right.keys);
+                left.values.append(&mut right.values);
+
+                if left.fits(block_size) {
+                    left.next = right.next;
+                    None
+                } else {
+                    let (new_sep, mut new_right) = left.split();
+                    new_right.next = right.next;
+                    new_right.prev = left_id;
+                    *right = new_right;
+                    Some(new_sep)
+                }
+            }
+            (Node::Inner(left), Node::Inner(right)) => {
+                // inner nodes pull their separator down from the parent, leaves already contain it
+                left.separators.push(parent_sep);
+                left.separators.append(&mut right.separators);
+                left.children.append(&mut right.children);
+
+                if left.fits(block_size) {
+                    None
+                } else {
+                    let (new_sep, new_right) = left.split();
+                    *right = new_right;
+                    Some(new_sep)
+                }
+            }
+            _ => return Err(NodeStoreError::InvalidReference),
+        };
 
-        // smallest key of the left node becomes the new separator
-        let root_sep = right_seps_slice[0];
+        let Some(new_sep) = new_sep else {
+            // unlink the right leaf from the chain
+            if right_next != 0 {
+                node_store.get_leaf(right_next)?.prev = left_id;
+            }
 
-        right_seps[0..target_size].copy_from_slice(right_seps_slice);
-        right_children[0..target_size].copy_from_slice(right_children_slice);
+            let mut parent = parent.inner_mut()?;
+            parent.separators.remove(left_index);
+            parent.children.remove(left_index + 1);
 
-        // insert elements that did not fit into the original node
-        right_seps[target_size] = largest_key;
-        right_children[target_size] = largest_value;
+            // a pinned node can't leave the cache
+            drop(right);
+            return node_store.free_node(right_id);
+        };
 
-        // update left node
-        right_seps_slice.fill(0);
-        right_children_slice.fill(0);
-        self.size = target_size;
+        parent.inner_mut()?.separators[left_index] = new_sep;
 
-        (root_sep, right_seps, right_children)
+        Ok(())
     }
 
     pub fn to_graphviz(&self, node_id: &NodeIdent) -> String {
-        if *node_id < 0 {
-            let mut result = format!("{} [shape=record,label=\"<sep0> ", node_id);
-            for i in 0..self.size {
-                result.push_str(&format!("| {} | <sep{}> ", self.separators[i], i + 1));
-            }
-            result.push_str("\"];");
-
-            for i in 0..=self.size {
-                result.push_str(&format!("\n{}:sep{} -> {};", node_id, i, self.children[i]));
-            }
+        match self {
+            Node::Leaf(leaf) => leaf.to_graphviz(node_id),
+            Node::Inner(inner) => inner.to_graphviz(node_id),
+        }
+    }
+}
 
-            result
-        } else {
-            let mut result = format!("{} [shape=record, label=\"", node_id);
-            for i in 0..self.size {
-                result.push_str(&format!("{{ {} }}", self.separators[i]));
-                if i < self.size - 1 {
-                    result.push_str(" | ");
-                }
-            }
-            result.push_str("\"];");
-            result
+/// formats the key so it can be used inside of a record label
+fn escape_label<K: Debug>(key: &K) -> String {
+    let mut label = String::new();
+    for c in format!("{:?}", key).chars() {
+        if matches!(c, '"' | '\\' | '{' | '}' | '|' | '<' | '>') {
+            label.push('\\');
         }
+        label.push(c);
     }
+    label
 }
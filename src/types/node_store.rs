@@ -1,10 +1,14 @@
-use std::cell::RefCell;
-use std::fmt::Debug;
+use std::cell::{Ref, RefCell, RefMut};
+use std::error::Error;
+use std::fmt::{self, Debug};
+use std::io::Write;
 use std::rc::Rc;
 
 use super::{
+    cache::{as_inner, as_leaf, CacheCapacity, PageGuard},
     file_store::Metadata,
-    node::{Node, NodeIdent},
+    node::{InnerNode, LeafNode, Node, NodeIdent, SearchKey, Value},
+    tree::TreeError,
 };
 
 #[derive(Debug)]
@@ -12,27 +16,330 @@ pub enum NodeStoreError {
     InvalidReference,
     WriteFailed,
     ReadFailed,
+    /// the block can't be decoded into what was asked for
+    Malformed {
+        block: NodeIdent,
+        reason: String,
+    },
+    /// the contents of the block in the file don't match the checksum written with them, e.g.
+    /// because of bit rot or a torn write
+    Corrupted {
+        block: NodeIdent,
+        expected: u32,
+        actual: u32,
+    },
+    /// the store can only be read from, e.g. because it belongs to a snapshot
+    ReadOnly,
 }
 
-pub trait NodeStore<T, const S: usize>
+impl fmt::Display for NodeStoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NodeStoreError::InvalidReference => write!(f, "the block does not exist"),
+            NodeStoreError::WriteFailed => write!(f, "unable to write to the file"),
+            NodeStoreError::ReadFailed => write!(f, "unable to read from the file"),
+            NodeStoreError::Malformed { block, reason } => {
+                write!(f, "block {block} is malformed: {reason}")
+            }
+            NodeStoreError::Corrupted {
+                block,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "the checksum {actual:08x} of block {block} does not match {expected:08x}"
+            ),
+            NodeStoreError::ReadOnly => write!(f, "the store is read only"),
+        }
+    }
+}
+
+impl Error for NodeStoreError {}
+
+/// identifies the commit a snapshot was taken after
+pub type SnapshotId = u64;
+
+pub trait NodeStore<K, T>
 where
-    T: Sized,
-    T: Debug,
+    K: SearchKey,
+    T: Value,
 {
-    fn get_node(&mut self, ident: NodeIdent) -> Result<&mut Node<T, S>, NodeStoreError>;
-    fn store_node(&mut self, node: Node<T, S>, is_leaf: bool) -> Result<NodeIdent, NodeStoreError>;
-    fn print_stored_nodes(&mut self, root: NodeIdent) -> ();
-    fn flush(&mut self) -> ();
-    fn set_metadata(&mut self, data: Metadata);
+    /// hands out the node to change it, so it is written back once it leaves the cache
+    fn get_node(&mut self, ident: NodeIdent) -> Result<RefMut<'_, Node<K, T>>, NodeStoreError>;
+    /// hands out the node to read it. Unlike `get_node`, reading a node never causes a write.
+    fn read_node(&mut self, ident: NodeIdent) -> Result<Ref<'_, Node<K, T>>, NodeStoreError>;
+    /// pins the node in the cache until the returned guard is dropped. Unlike the nodes handed
+    /// out by `get_node`, a pinned node can be held while the store is used for other nodes.
+    fn pin_node(&mut self, ident: NodeIdent) -> Result<PageGuard<K, T>, NodeStoreError>;
+    fn store_node(&mut self, node: Node<K, T>) -> Result<NodeIdent, NodeStoreError>;
+    /// releases the node so its block can be reused by a later `store_node`
+    fn free_node(&mut self, ident: NodeIdent) -> Result<(), NodeStoreError>;
+    /// writes the bytes into a chain of overflow blocks and returns the first block of the chain
+    fn store_overflow(&mut self, bytes: &[u8]) -> Result<NodeIdent, NodeStoreError>;
+    /// reads `len` bytes back from the overflow chain starting at `first`
+    fn load_overflow(&mut self, first: NodeIdent, len: usize) -> Result<Vec<u8>, NodeStoreError>;
+    /// releases every block of the overflow chain starting at `first`
+    fn free_overflow(&mut self, first: NodeIdent) -> Result<(), NodeStoreError>;
+    fn flush(&mut self) -> Result<(), NodeStoreError>;
+    fn set_metadata(&mut self, data: Metadata) -> Result<(), NodeStoreError>;
+    /// whether any block was changed, allocated or released since the last commit
+    fn has_changes(&self) -> bool;
+    /// makes all changes since the last commit durable. After a crash the store is recovered to
+    /// the state of the last commit.
+    fn commit(&mut self) -> Result<(), NodeStoreError>;
+    /// throws away all changes since the last commit
+    fn rollback(&mut self);
+    /// makes sure the committed changes no longer depend on the log
+    fn checkpoint(&mut self) -> Result<(), NodeStoreError>;
     fn node_ctr(&self) -> NodeIdent;
+    fn free_list_head(&self) -> NodeIdent;
+    /// size of the blocks of the store in bytes
+    fn block_size(&self) -> usize;
+    /// changes how many nodes the store caches, writing back the nodes that no longer fit
+    fn set_cache_capacity(&mut self, capacity: CacheCapacity) -> Result<(), NodeStoreError>;
+    /// keeps the state of the last commit readable through `snapshot_block` until the snapshot is
+    /// released, no matter what is committed in the meantime. Returns the metadata of that state.
+    fn snapshot(&mut self) -> Result<(SnapshotId, Metadata), NodeStoreError>;
+    /// reads a block as it was when the snapshot was taken
+    fn snapshot_block(
+        &mut self,
+        snapshot: SnapshotId,
+        index: usize,
+    ) -> Result<Block, NodeStoreError>;
+    /// allows the blocks only the snapshot was using to be reused
+    fn release_snapshot(&mut self, snapshot: SnapshotId);
+
+    /// writes the nodes reachable from `root` in the DOT format
+    fn print_stored_nodes(
+        &mut self,
+        root: NodeIdent,
+        out: &mut dyn Write,
+    ) -> Result<(), TreeError> {
+        let mut node_stack: Vec<NodeIdent> = vec![root];
+        while let Some(i) = node_stack.pop() {
+            let node = self.read_node(i)?;
+            writeln!(out, "{}", node.to_graphviz(&i))?;
+            if let Node::Inner(inner) = &*node {
+                inner.children.iter().for_each(|i| node_stack.push(*i));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// like `get_node`, but fails if the node is not a leaf
+    fn get_leaf(&mut self, ident: NodeIdent) -> Result<RefMut<'_, LeafNode<K, T>>, NodeStoreError> {
+        as_leaf(self.get_node(ident)?)
+    }
+
+    /// like `get_node`, but fails if the node is not an inner node
+    fn get_inner<'a>(
+        &'a mut self,
+        ident: NodeIdent,
+    ) -> Result<RefMut<'a, InnerNode<K>>, NodeStoreError>
+    where
+        T: 'a,
+    {
+        as_inner(self.get_node(ident)?)
+    }
+
+    /// like `read_node`, but fails if the node is not a leaf
+    fn read_leaf(&mut self, ident: NodeIdent) -> Result<Ref<'_, LeafNode<K, T>>, NodeStoreError> {
+        Ref::filter_map(self.read_node(ident)?, |node| match node {
+            Node::Leaf(leaf) => Some(leaf),
+            Node::Inner(_) => None,
+        })
+        .map_err(|_| NodeStoreError::InvalidReference)
+    }
+
+    /// like `read_node`, but fails if the node is not an inner node
+    fn read_inner<'a>(
+        &'a mut self,
+        ident: NodeIdent,
+    ) -> Result<Ref<'a, InnerNode<K>>, NodeStoreError>
+    where
+        T: 'a,
+    {
+        Ref::filter_map(self.read_node(ident)?, |node| match node {
+            Node::Inner(inner) => Some(inner),
+            Node::Leaf(_) => None,
+        })
+        .map_err(|_| NodeStoreError::InvalidReference)
+    }
 }
 
-pub type SharedNodeStore<T, const S: usize> = Rc<RefCell<dyn NodeStore<T, S>>>;
+pub type SharedNodeStore<K, T> = Rc<RefCell<dyn NodeStore<K, T>>>;
 
-/// size of the file blocks in bytes
-pub const BLOCK_SIZE: usize = 128;
+/// size of the file blocks in bytes used if none is given
+pub const DEFAULT_BLOCK_SIZE: usize = 4096;
+/// smallest block size a tree can be created with, every block size has to be a power of two
+pub const MIN_BLOCK_SIZE: usize = 128;
+/// largest block size a tree can be created with, offsets into a block are stored as `u16`
+pub const MAX_BLOCK_SIZE: usize = 1 << 16;
+
+/// whether a tree can be stored in blocks of the size
+pub fn valid_block_size(block_size: usize) -> bool {
+    block_size.is_power_of_two() && (MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&block_size)
+}
+
+/// contents of a single block, as many bytes as the block size of its store
+pub type Block = Vec<u8>;
 
 pub trait ByteSerialize {
-    fn to_bytes(&self) -> [u8; BLOCK_SIZE];
-    fn from_bytes(block: [u8; BLOCK_SIZE]) -> Self;
+    fn to_bytes(&self, block_size: usize) -> Block;
+    fn from_bytes(block: &[u8]) -> Self;
+}
+
+/// Types that can be written into the blocks of a node store. The encoding of a value may have a
+/// different length for each value, e.g. for strings.
+pub trait Encode: Sized {
+    /// the size of every encoded value, if it is the same for all of them
+    const FIXED_SIZE: Option<usize> = None;
+
+    fn encoded_size(&self) -> usize;
+    /// writes the encoding into the first `encoded_size()` bytes of the buffer
+    fn encode(&self, buf: &mut [u8]);
+    /// reads the value back from a buffer holding exactly its encoding, `None` if the bytes are
+    /// not a valid encoding
+    fn decode(buf: &[u8]) -> Option<Self>;
+
+    fn to_vec(&self) -> Vec<u8> {
+        let mut buf = vec![0; self.encoded_size()];
+        self.encode(&mut buf);
+        buf
+    }
+}
+
+impl<F> Encode for F
+where
+    F: FixedSize,
+{
+    const FIXED_SIZE: Option<usize> = Some(F::ENCODED_SIZE);
+
+    fn encoded_size(&self) -> usize {
+        F::ENCODED_SIZE
+    }
+
+    fn encode(&self, buf: &mut [u8]) {
+        FixedSize::encode(self, buf)
+    }
+
+    fn decode(buf: &[u8]) -> Option<Self> {
+        Some(FixedSize::decode(buf))
+    }
+}
+
+impl Encode for Vec<u8> {
+    fn encoded_size(&self) -> usize {
+        self.len()
+    }
+
+    fn encode(&self, buf: &mut [u8]) {
+        buf[0..self.len()].copy_from_slice(self);
+    }
+
+    fn decode(buf: &[u8]) -> Option<Self> {
+        Some(buf.to_vec())
+    }
+}
+
+impl Encode for String {
+    fn encoded_size(&self) -> usize {
+        self.len()
+    }
+
+    fn encode(&self, buf: &mut [u8]) {
+        buf[0..self.len()].copy_from_slice(self.as_bytes());
+    }
+
+    /// the bytes have to be valid UTF-8, replacing invalid ones would change the order of keys
+    fn decode(buf: &[u8]) -> Option<Self> {
+        String::from_utf8(buf.to_vec()).ok()
+    }
+}
+
+/// Types that always encode to the same amount of bytes
+pub trait FixedSize: Sized {
+    const ENCODED_SIZE: usize;
+
+    /// writes the encoding into the first `ENCODED_SIZE` bytes of the buffer
+    fn encode(&self, buf: &mut [u8]);
+    /// reads the value back from the first `ENCODED_SIZE` bytes of the buffer
+    fn decode(buf: &[u8]) -> Self;
+}
+
+macro_rules! impl_fixed_size_int {
+    ($($t:ty),*) => {
+        $(
+            impl FixedSize for $t {
+                const ENCODED_SIZE: usize = size_of::<$t>();
+
+                fn encode(&self, buf: &mut [u8]) {
+                    buf[0..Self::ENCODED_SIZE].copy_from_slice(&self.to_le_bytes());
+                }
+
+                fn decode(buf: &[u8]) -> Self {
+                    let mut entry = [0; size_of::<$t>()];
+                    entry.copy_from_slice(&buf[0..Self::ENCODED_SIZE]);
+                    <$t>::from_le_bytes(entry)
+                }
+            }
+        )*
+    };
+}
+
+impl_fixed_size_int!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128);
+
+impl<const N: usize> FixedSize for [u8; N] {
+    const ENCODED_SIZE: usize = N;
+
+    fn encode(&self, buf: &mut [u8]) {
+        buf[0..N].copy_from_slice(self);
+    }
+
+    fn decode(buf: &[u8]) -> Self {
+        let mut bytes = [0; N];
+        bytes.copy_from_slice(&buf[0..N]);
+        bytes
+    }
+}
+
+impl<A, B> FixedSize for (A, B)
+where
+    A: FixedSize,
+    B: FixedSize,
+{
+    const ENCODED_SIZE: usize = A::ENCODED_SIZE + B::ENCODED_SIZE;
+
+    fn encode(&self, buf: &mut [u8]) {
+        self.0.encode(buf);
+        self.1.encode(&mut buf[A::ENCODED_SIZE..]);
+    }
+
+    fn decode(buf: &[u8]) -> Self {
+        (A::decode(buf), B::decode(&buf[A::ENCODED_SIZE..]))
+    }
+}
+
+impl<A, B, C> FixedSize for (A, B, C)
+where
+    A: FixedSize,
+    B: FixedSize,
+    C: FixedSize,
+{
+    const ENCODED_SIZE: usize = A::ENCODED_SIZE + B::ENCODED_SIZE + C::ENCODED_SIZE;
+
+    fn encode(&self, buf: &mut [u8]) {
+        self.0.encode(buf);
+        self.1.encode(&mut buf[A::ENCODED_SIZE..]);
+        self.2.encode(&mut buf[A::ENCODED_SIZE + B::ENCODED_SIZE..]);
+    }
+
+    fn decode(buf: &[u8]) -> Self {
+        (
+            A::decode(buf),
+            B::decode(&buf[A::ENCODED_SIZE..]),
+            C::decode(&buf[A::ENCODED_SIZE + B::ENCODED_SIZE..]),
+        )
+    }
 }
@@ -0,0 +1,332 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::File,
+    mem,
+};
+
+use super::{
+    file_store::{payload_size, read_block_at, write_block_at},
+    node::NodeIdent,
+    node_store::{Block, NodeStoreError, SnapshotId},
+};
+
+/// entries held by each block of the page table
+fn table_fanout(block_size: usize) -> usize {
+    payload_size(block_size) / size_of::<NodeIdent>()
+}
+
+/// Maps the blocks handed out by a shadow paged store to the places in the tree file they are
+/// currently stored at. A commit never overwrites a place the committed state uses: changed blocks
+/// and the parts of the table leading to them are written to unused places, and only become
+/// visible once the metadata is pointed at the new root of the table. Until then, the file still
+/// holds the complete previous state.
+pub struct PageTable {
+    /// `levels[0]` holds the place of every block, each further level the places of the table
+    /// blocks storing the level below it. The last level only holds the root of the table.
+    levels: Vec<Vec<NodeIdent>>,
+    /// places not used by the committed state
+    free: BTreeSet<NodeIdent>,
+    /// amount of places in the file
+    len: NodeIdent,
+    /// places replaced by a commit while snapshots of older states were alive, by the commit that
+    /// replaced them
+    held: BTreeMap<SnapshotId, Vec<NodeIdent>>,
+    // state of the commit in progress, needed to publish or revert it
+    allocated: Vec<NodeIdent>,
+    retired: Vec<NodeIdent>,
+    undo: Vec<(usize, usize, NodeIdent)>,
+    committed_lens: Vec<usize>,
+    block_size: usize,
+}
+
+/// amount of entries in each level of a table holding the places of the blocks up to `node_ctr`
+fn level_lens(node_ctr: NodeIdent, fanout: usize) -> Vec<usize> {
+    let mut lens = vec![node_ctr as usize + 1];
+    while lens.len() == 1 || lens[lens.len() - 1] > 1 {
+        lens.push(lens[lens.len() - 1].div_ceil(fanout));
+    }
+
+    lens
+}
+
+fn read_entry(block: &[u8], index: usize) -> NodeIdent {
+    let mut entry = [0; size_of::<NodeIdent>()];
+    entry.copy_from_slice(
+        &block[index * size_of::<NodeIdent>()..(index + 1) * size_of::<NodeIdent>()],
+    );
+    NodeIdent::from_le_bytes(entry)
+}
+
+impl PageTable {
+    /// table of a new file with blocks of `block_size` bytes, which only holds the metadata block
+    pub fn new(block_size: usize) -> Self {
+        PageTable {
+            levels: vec![vec![0]],
+            free: BTreeSet::new(),
+            len: 1,
+            held: BTreeMap::new(),
+            allocated: vec![],
+            retired: vec![],
+            undo: vec![],
+            committed_lens: vec![],
+            block_size,
+        }
+    }
+
+    /// reads the table with its root at `root`, holding the places of the blocks up to `node_ctr`
+    pub fn load(
+        file: &File,
+        root: NodeIdent,
+        node_ctr: NodeIdent,
+        block_size: usize,
+    ) -> Result<Self, NodeStoreError> {
+        let fanout = table_fanout(block_size);
+        let lens = level_lens(node_ctr, fanout);
+
+        // read the levels from the root down
+        let mut levels = vec![vec![root]];
+        for &len in lens.iter().rev().skip(1) {
+            let mut level = Vec::with_capacity(len);
+            for &place in &levels[levels.len() - 1] {
+                // table blocks that never held anything are not written
+                let block = match place {
+                    0 => vec![0; block_size],
+                    place => read_block_at(file, place as usize, block_size)?,
+                };
+                level.extend((0..fanout).map(|i| read_entry(&block, i)));
+            }
+            level.truncate(len);
+            levels.push(level);
+        }
+        levels.reverse();
+
+        // everything not reachable from the root is left over from older commits
+        let used: BTreeSet<NodeIdent> = levels.iter().flatten().copied().collect();
+        let file_size = file
+            .metadata()
+            .map_err(|_| NodeStoreError::ReadFailed)?
+            .len();
+        let file_len = (file_size / block_size as u64) as NodeIdent;
+        let len = file_len.max(used.last().map_or(0, |last| last + 1)).max(1);
+        let free = (1..len).filter(|place| !used.contains(place)).collect();
+
+        Ok(PageTable {
+            levels,
+            free,
+            len,
+            held: BTreeMap::new(),
+            allocated: vec![],
+            retired: vec![],
+            undo: vec![],
+            committed_lens: vec![],
+            block_size,
+        })
+    }
+
+    /// place of the block in the file, `None` if it was never committed
+    pub fn get(&self, index: usize) -> Option<usize> {
+        // the metadata is always written in place
+        if index == 0 {
+            return Some(0);
+        }
+
+        match self.levels[0].get(index) {
+            Some(&place) if place != 0 => Some(place as usize),
+            _ => None,
+        }
+    }
+
+    /// place of the block in the committed table with its root at `root`, reading the table from
+    /// the file. This works for older tables as long as the places they use are held.
+    pub fn get_committed(
+        file: &File,
+        root: NodeIdent,
+        node_ctr: NodeIdent,
+        block_size: usize,
+        index: usize,
+    ) -> Result<usize, NodeStoreError> {
+        if index == 0 {
+            return Ok(0);
+        }
+        if index > node_ctr as usize {
+            return Err(NodeStoreError::InvalidReference);
+        }
+
+        // every level below the root splits the blocks into groups of `fanout`
+        let fanout = table_fanout(block_size);
+        let mut place = root;
+        for level in (0..level_lens(node_ctr, fanout).len() - 1).rev() {
+            if place == 0 {
+                return Err(NodeStoreError::InvalidReference);
+            }
+
+            let block = read_block_at(file, place as usize, block_size)?;
+            place = read_entry(&block, index / fanout.pow(level as u32) % fanout);
+        }
+
+        match place {
+            0 => Err(NodeStoreError::InvalidReference),
+            place => Ok(place as usize),
+        }
+    }
+
+    /// writes the blocks (except for the metadata) and the parts of the table leading to them to
+    /// unused places and syncs them. Returns the root of the new table, which has to be published
+    /// by pointing the metadata at it. If this fails, the table is left as it was.
+    pub fn stage(
+        &mut self,
+        file: &File,
+        blocks: &BTreeMap<usize, Block>,
+        node_ctr: NodeIdent,
+    ) -> Result<NodeIdent, NodeStoreError> {
+        self.committed_lens = self.levels.iter().map(Vec::len).collect();
+
+        let result = self.write_table(file, blocks, node_ctr);
+        if result.is_err() {
+            self.revert();
+        }
+
+        result
+    }
+
+    fn write_table(
+        &mut self,
+        file: &File,
+        blocks: &BTreeMap<usize, Block>,
+        node_ctr: NodeIdent,
+    ) -> Result<NodeIdent, NodeStoreError> {
+        let fanout = table_fanout(self.block_size);
+        let last = blocks.keys().next_back().copied().unwrap_or(0);
+        let entries = (node_ctr as usize).max(last) + 1;
+        if entries > self.levels[0].len() {
+            self.levels[0].resize(entries, 0);
+        }
+
+        let mut dirty = BTreeSet::new();
+        for (&index, block) in blocks.iter().filter(|(&index, _)| index != 0) {
+            let place = self.allocate();
+            write_block_at(file, place as usize, block)?;
+            self.set(0, index, place);
+            dirty.insert(index);
+        }
+
+        // rewrite every table block below a changed entry, up to the root
+        let mut level = 0;
+        while level == 0 || self.levels[level].len() > 1 {
+            let len = self.levels[level].len().div_ceil(fanout);
+            if self.levels.len() == level + 1 {
+                self.levels.push(vec![]);
+            }
+            let old_len = self.levels[level + 1].len();
+            if len > old_len {
+                self.levels[level + 1].resize(len, 0);
+            }
+
+            let parents: BTreeSet<usize> = dirty
+                .iter()
+                .map(|index| index / fanout)
+                .chain(old_len..len)
+                .collect();
+            for &parent in &parents {
+                let start = parent * fanout;
+                let end = (start + fanout).min(self.levels[level].len());
+
+                let mut block = vec![0; self.block_size];
+                for (entry, place) in block
+                    .chunks_mut(size_of::<NodeIdent>())
+                    .zip(&self.levels[level][start..end])
+                {
+                    entry.copy_from_slice(&place.to_le_bytes());
+                }
+
+                let place = self.allocate();
+                write_block_at(file, place as usize, &block)?;
+                self.set(level + 1, parent, place);
+            }
+
+            dirty = parents;
+            level += 1;
+        }
+
+        file.sync_data().map_err(|_| NodeStoreError::WriteFailed)?;
+
+        Ok(self.levels[level][0])
+    }
+
+    /// must be called once the metadata points at the staged table. The places it replaced are
+    /// free from now on, unless snapshots still need them. Those are held as part of `commit`
+    /// until `release` is called.
+    pub fn publish(&mut self, commit: Option<SnapshotId>) {
+        match commit {
+            Some(commit) => self
+                .held
+                .entry(commit)
+                .or_default()
+                .append(&mut self.retired),
+            None => self.free.extend(self.retired.drain(..)),
+        }
+        self.allocated.clear();
+        self.undo.clear();
+    }
+
+    /// frees the held places no snapshot needs anymore, given the oldest one still alive
+    pub fn release(&mut self, oldest: Option<SnapshotId>) {
+        // places replaced by a commit are only used by snapshots taken before it
+        let kept = match oldest {
+            Some(oldest) => self.held.split_off(&(oldest + 1)),
+            None => BTreeMap::new(),
+        };
+
+        for (_, places) in mem::replace(&mut self.held, kept) {
+            self.free.extend(places);
+        }
+    }
+
+    /// throws the staged table away, its places are unused again
+    pub fn revert(&mut self) {
+        self.restore_committed();
+        self.free.extend(self.allocated.drain(..));
+    }
+
+    /// throws the staged table away, but keeps its places out of use. Needed if the metadata
+    /// pointing at it may have reached the file, so a crash could still leave it as the committed
+    /// table. The places are only found to be free again once the file is loaded.
+    pub fn abandon(&mut self) {
+        self.restore_committed();
+        self.allocated.clear();
+    }
+
+    /// points the table back at the places of the last commit
+    fn restore_committed(&mut self) {
+        for (level, index, place) in self.undo.drain(..).rev() {
+            self.levels[level][index] = place;
+        }
+
+        self.levels.truncate(self.committed_lens.len());
+        for (level, &len) in self.levels.iter_mut().zip(&self.committed_lens) {
+            level.truncate(len);
+        }
+
+        self.retired.clear();
+    }
+
+    /// returns an unused place, reusing the front of the file before growing it
+    fn allocate(&mut self) -> NodeIdent {
+        let place = self.free.pop_first().unwrap_or_else(|| {
+            self.len += 1;
+            self.len - 1
+        });
+        self.allocated.push(place);
+
+        place
+    }
+
+    fn set(&mut self, level: usize, index: usize, place: NodeIdent) {
+        let old = mem::replace(&mut self.levels[level][index], place);
+        self.undo.push((level, index, old));
+
+        if old != 0 {
+            self.retired.push(old);
+        }
+    }
+}
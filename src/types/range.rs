@@ -0,0 +1,272 @@
+use std::{cell::RefCell, collections::VecDeque, ops::Bound};
+
+use super::{
+    node::{LeafValue, Node, NodeIdent, SearchKey, Value},
+    node_store::{NodeStore, NodeStoreError},
+    tree::TreeError,
+};
+
+/// Iterator over the entries of a tree in key order. The first leaf on each end is found by
+/// descending from the root, after that the iterator follows the links between the leaves. It
+/// borrows the tree, so the leaves it follows can't be changed or freed while it is alive.
+pub struct Range<'a, K, T>
+where
+    K: SearchKey,
+    T: Value,
+{
+    store: &'a RefCell<dyn NodeStore<K, T>>,
+    root: NodeIdent,
+    // bounds of the keys that have not been returned yet
+    front: Bound<K>,
+    back: Bound<K>,
+    next_front_leaf: LeafCursor,
+    next_back_leaf: LeafCursor,
+    // values are only read from their overflow blocks once they are returned
+    front_buffer: VecDeque<(K, LeafValue<T>)>,
+    back_buffer: VecDeque<(K, LeafValue<T>)>,
+}
+
+/// the leaf an end of the iterator will load once its buffer runs out
+#[derive(Clone, Copy)]
+enum LeafCursor {
+    Descend,
+    Leaf(NodeIdent),
+    Done,
+}
+
+impl LeafCursor {
+    fn from_link(link: NodeIdent) -> Self {
+        if link == 0 {
+            LeafCursor::Done
+        } else {
+            LeafCursor::Leaf(link)
+        }
+    }
+}
+
+struct LoadedLeaf<K, T> {
+    entries: Vec<(K, LeafValue<T>)>,
+    next: NodeIdent,
+    prev: NodeIdent,
+}
+
+fn is_above<K: SearchKey>(key: &K, bound: &Bound<K>) -> bool {
+    match bound {
+        Bound::Included(b) => key >= b,
+        Bound::Excluded(b) => key > b,
+        Bound::Unbounded => true,
+    }
+}
+
+fn is_below<K: SearchKey>(key: &K, bound: &Bound<K>) -> bool {
+    match bound {
+        Bound::Included(b) => key <= b,
+        Bound::Excluded(b) => key < b,
+        Bound::Unbounded => true,
+    }
+}
+
+impl<'a, K, T> Range<'a, K, T>
+where
+    K: SearchKey,
+    T: Value,
+{
+    pub fn new(
+        store: &'a RefCell<dyn NodeStore<K, T>>,
+        root: NodeIdent,
+        front: Bound<K>,
+        back: Bound<K>,
+    ) -> Self {
+        Range {
+            store,
+            root,
+            front,
+            back,
+            next_front_leaf: LeafCursor::Descend,
+            next_back_leaf: LeafCursor::Descend,
+            front_buffer: VecDeque::new(),
+            back_buffer: VecDeque::new(),
+        }
+    }
+
+    /// descends to the leaf containing the first key within `bound` when walking in the given
+    /// direction
+    fn find_leaf(&self, bound: &Bound<K>, forward: bool) -> Result<NodeIdent, NodeStoreError> {
+        let mut node_store = self.store.borrow_mut();
+        let mut ident = self.root;
+
+        while let Node::Inner(node) = &*node_store.read_node(ident)? {
+            let separators = &node.separators;
+
+            let index = match (bound, forward) {
+                (Bound::Unbounded, true) => 0,
+                (Bound::Unbounded, false) => separators.len(),
+                (Bound::Excluded(key), false) => separators.partition_point(|sep| sep < key),
+                (Bound::Included(key), _) | (Bound::Excluded(key), true) => {
+                    separators.partition_point(|sep| sep <= key)
+                }
+            };
+
+            ident = node.children[index];
+        }
+
+        Ok(ident)
+    }
+
+    fn load_leaf(
+        &self,
+        cursor: LeafCursor,
+        forward: bool,
+    ) -> Result<Option<LoadedLeaf<K, T>>, NodeStoreError> {
+        let ident = match cursor {
+            LeafCursor::Descend if forward => self.find_leaf(&self.front, true)?,
+            LeafCursor::Descend => self.find_leaf(&self.back, false)?,
+            LeafCursor::Leaf(ident) => ident,
+            LeafCursor::Done => return Ok(None),
+        };
+
+        let mut node_store = self.store.borrow_mut();
+        let leaf = node_store.read_leaf(ident)?;
+
+        Ok(Some(LoadedLeaf {
+            entries: leaf
+                .keys
+                .iter()
+                .cloned()
+                .zip(leaf.values.iter().cloned())
+                .collect(),
+            next: leaf.next,
+            prev: leaf.prev,
+        }))
+    }
+
+    fn fill_front(&mut self) -> Result<(), NodeStoreError> {
+        while self.front_buffer.is_empty() {
+            let Some(leaf) = self.load_leaf(self.next_front_leaf, true)? else {
+                return Ok(());
+            };
+
+            // there is no need to look at the following leaves once we walked past the back
+            let past_back = leaf
+                .entries
+                .last()
+                .is_some_and(|(key, _)| !is_below(key, &self.back));
+            self.next_front_leaf = if past_back {
+                LeafCursor::Done
+            } else {
+                LeafCursor::from_link(leaf.next)
+            };
+
+            self.front_buffer.extend(
+                leaf.entries
+                    .into_iter()
+                    .filter(|(key, _)| is_above(key, &self.front) && is_below(key, &self.back)),
+            );
+        }
+
+        Ok(())
+    }
+
+    fn fill_back(&mut self) -> Result<(), NodeStoreError> {
+        while self.back_buffer.is_empty() {
+            let Some(leaf) = self.load_leaf(self.next_back_leaf, false)? else {
+                return Ok(());
+            };
+
+            let past_front = leaf
+                .entries
+                .first()
+                .is_some_and(|(key, _)| !is_above(key, &self.front));
+            self.next_back_leaf = if past_front {
+                LeafCursor::Done
+            } else {
+                LeafCursor::from_link(leaf.prev)
+            };
+
+            self.back_buffer.extend(
+                leaf.entries
+                    .into_iter()
+                    .filter(|(key, _)| is_above(key, &self.front) && is_below(key, &self.back)),
+            );
+        }
+
+        Ok(())
+    }
+
+    fn resolve(&self, value: LeafValue<T>) -> Result<T, NodeStoreError> {
+        value.resolve(&mut *self.store.borrow_mut())
+    }
+
+    fn finish(&mut self) {
+        self.next_front_leaf = LeafCursor::Done;
+        self.next_back_leaf = LeafCursor::Done;
+        self.front_buffer.clear();
+        self.back_buffer.clear();
+    }
+}
+
+impl<K, T> Iterator for Range<'_, K, T>
+where
+    K: SearchKey,
+    T: Value,
+{
+    type Item = Result<(K, T), TreeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Err(e) = self.fill_front() {
+            self.finish();
+            return Some(Err(e.into()));
+        }
+
+        let (key, value) = self.front_buffer.pop_front()?;
+
+        // the back may have already returned this entry
+        if !is_below(&key, &self.back) {
+            self.finish();
+            return None;
+        }
+
+        let value = match self.resolve(value) {
+            Ok(v) => v,
+            Err(e) => {
+                self.finish();
+                return Some(Err(e.into()));
+            }
+        };
+
+        self.front = Bound::Excluded(key.clone());
+        Some(Ok((key, value)))
+    }
+}
+
+impl<K, T> DoubleEndedIterator for Range<'_, K, T>
+where
+    K: SearchKey,
+    T: Value,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if let Err(e) = self.fill_back() {
+            self.finish();
+            return Some(Err(e.into()));
+        }
+
+        let (key, value) = self.back_buffer.pop_back()?;
+
+        // the front may have already returned this entry
+        if !is_above(&key, &self.front) {
+            self.finish();
+            return None;
+        }
+
+        let value = match self.resolve(value) {
+            Ok(v) => v,
+            Err(e) => {
+                self.finish();
+                return Some(Err(e.into()));
+            }
+        };
+
+        self.back = Bound::Excluded(key.clone());
+        Some(Ok((key, value)))
+    }
+}
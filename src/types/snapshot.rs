@@ -0,0 +1,312 @@
+use std::{
+    cell::{Ref, RefCell, RefMut},
+    ops::RangeBounds,
+    rc::Rc,
+};
+
+use super::{
+    cache::{Cache, CacheCapacity, CacheConfig, PageGuard},
+    file_store::{read_overflow, Metadata},
+    node::{Node, NodeIdent, SearchKey, Value},
+    node_store::{Block, NodeStore, NodeStoreError, SharedNodeStore, SnapshotId},
+    range::Range,
+    tree::TreeError,
+    verify::{verify, VerifyReport},
+};
+
+/// Read-only view of a tree as of the commit it was taken after. The tree can keep changing in the
+/// meantime, the nodes reachable from the snapshot stay as they were until it is dropped.
+pub struct TreeSnapshot<K, T>
+where
+    K: SearchKey,
+    T: Value,
+{
+    store: Rc<RefCell<SnapshotStore<K, T>>>,
+    root: NodeIdent,
+}
+
+/// Node store reading the blocks of a snapshot from the store of its tree. The snapshot is
+/// released once the store is dropped.
+pub struct SnapshotStore<K, T>
+where
+    K: SearchKey,
+    T: Value,
+{
+    store: SharedNodeStore<K, T>,
+    snapshot: SnapshotId,
+    metadata: Metadata,
+    cache: Cache<K, T>,
+}
+
+impl<K, T> TreeSnapshot<K, T>
+where
+    K: SearchKey + 'static,
+    T: Value + 'static,
+{
+    /// takes a snapshot of the last commit of the store, `cache` sets up the cache of its nodes
+    pub fn new(store: SharedNodeStore<K, T>, cache: CacheConfig) -> Result<Self, TreeError> {
+        let (snapshot, metadata) = store.borrow_mut().snapshot()?;
+        let root = metadata.root_node;
+        let cache = Cache::new(cache.replacement, cache.capacity.nodes(metadata.block_size));
+
+        Ok(TreeSnapshot {
+            store: Rc::new(RefCell::new(SnapshotStore {
+                store,
+                snapshot,
+                metadata,
+                cache,
+            })),
+            root,
+        })
+    }
+
+    pub fn search(&self, key: &K) -> Result<Option<T>, TreeError> {
+        Ok(Node::search(self.root, key, self.store.clone())?)
+    }
+
+    /// iterates over all entries with keys inside the given range in key order
+    pub fn range<R>(&self, range: R) -> Range<'_, K, T>
+    where
+        R: RangeBounds<K>,
+    {
+        let front = range.start_bound().cloned();
+        let back = range.end_bound().cloned();
+
+        Range::new(&*self.store, self.root, front, back)
+    }
+
+    /// iterates over all entries in key order
+    pub fn iter(&self) -> Range<'_, K, T> {
+        self.range(..)
+    }
+
+    /// checks every block of the snapshot against the invariants of the tree
+    pub fn verify(&self) -> VerifyReport {
+        let mut store = self.store.borrow_mut();
+        let metadata = store.metadata.clone();
+
+        verify::<K, T>(&metadata, |index| store.read_block(index))
+    }
+}
+
+impl<K, T> SnapshotStore<K, T>
+where
+    K: SearchKey,
+    T: Value,
+{
+    /// reads the raw contents of a block of the snapshot
+    pub fn read_block(&mut self, index: usize) -> Result<Block, NodeStoreError> {
+        self.store.borrow_mut().snapshot_block(self.snapshot, index)
+    }
+}
+
+impl<K, T> SnapshotStore<K, T>
+where
+    K: SearchKey,
+    T: Value,
+{
+    /// makes sure the node is cached, reading it from the snapshot if it isn't
+    fn load_node(&mut self, ident: NodeIdent) -> Result<(), NodeStoreError> {
+        if self.cache.has_node(ident) {
+            return Ok(());
+        }
+
+        let block = {
+            let mut store = self.store.borrow_mut();
+            store.snapshot_block(self.snapshot, ident as usize)?
+        };
+        let node = Node::from_bytes(ident, &block)?;
+
+        // nodes of a snapshot never change, so evicted ones don't have to be written back
+        self.cache.cache_node(ident, node);
+
+        Ok(())
+    }
+}
+
+impl<K, T> NodeStore<K, T> for SnapshotStore<K, T>
+where
+    K: SearchKey,
+    T: Value,
+{
+    fn get_node(&mut self, _ident: NodeIdent) -> Result<RefMut<'_, Node<K, T>>, NodeStoreError> {
+        Err(NodeStoreError::ReadOnly)
+    }
+
+    fn read_node(&mut self, ident: NodeIdent) -> Result<Ref<'_, Node<K, T>>, NodeStoreError> {
+        self.load_node(ident)?;
+
+        Ok(self.cache.get_node(ident).unwrap())
+    }
+
+    fn pin_node(&mut self, ident: NodeIdent) -> Result<PageGuard<K, T>, NodeStoreError> {
+        self.load_node(ident)?;
+
+        Ok(self.cache.pin(ident).unwrap())
+    }
+
+    fn store_node(&mut self, _node: Node<K, T>) -> Result<NodeIdent, NodeStoreError> {
+        Err(NodeStoreError::ReadOnly)
+    }
+
+    fn free_node(&mut self, _ident: NodeIdent) -> Result<(), NodeStoreError> {
+        Err(NodeStoreError::ReadOnly)
+    }
+
+    fn store_overflow(&mut self, _bytes: &[u8]) -> Result<NodeIdent, NodeStoreError> {
+        Err(NodeStoreError::ReadOnly)
+    }
+
+    fn load_overflow(&mut self, first: NodeIdent, len: usize) -> Result<Vec<u8>, NodeStoreError> {
+        let snapshot = self.snapshot;
+        let mut store = self.store.borrow_mut();
+        read_overflow(first, len, |index| store.snapshot_block(snapshot, index))
+    }
+
+    fn free_overflow(&mut self, _first: NodeIdent) -> Result<(), NodeStoreError> {
+        Err(NodeStoreError::ReadOnly)
+    }
+
+    fn flush(&mut self) -> Result<(), NodeStoreError> {
+        Ok(())
+    }
+
+    fn set_metadata(&mut self, _data: Metadata) -> Result<(), NodeStoreError> {
+        Err(NodeStoreError::ReadOnly)
+    }
+
+    fn has_changes(&self) -> bool {
+        false
+    }
+
+    fn commit(&mut self) -> Result<(), NodeStoreError> {
+        Ok(())
+    }
+
+    fn rollback(&mut self) {}
+
+    fn checkpoint(&mut self) -> Result<(), NodeStoreError> {
+        Ok(())
+    }
+
+    fn node_ctr(&self) -> NodeIdent {
+        self.metadata.node_ctr
+    }
+
+    fn free_list_head(&self) -> NodeIdent {
+        self.metadata.free_list_head
+    }
+
+    fn block_size(&self) -> usize {
+        self.metadata.block_size
+    }
+
+    fn set_cache_capacity(&mut self, capacity: CacheCapacity) -> Result<(), NodeStoreError> {
+        // the nodes of a snapshot can't change, so they can simply be dropped
+        self.cache.resize(capacity.nodes(self.metadata.block_size));
+        Ok(())
+    }
+
+    fn snapshot(&mut self) -> Result<(SnapshotId, Metadata), NodeStoreError> {
+        // snapshots are only taken of the tree itself
+        Err(NodeStoreError::ReadOnly)
+    }
+
+    fn snapshot_block(
+        &mut self,
+        snapshot: SnapshotId,
+        index: usize,
+    ) -> Result<Block, NodeStoreError> {
+        self.store.borrow_mut().snapshot_block(snapshot, index)
+    }
+
+    fn release_snapshot(&mut self, snapshot: SnapshotId) {
+        self.store.borrow_mut().release_snapshot(snapshot)
+    }
+}
+
+impl<K, T> Drop for SnapshotStore<K, T>
+where
+    K: SearchKey,
+    T: Value,
+{
+    fn drop(&mut self) {
+        self.store.borrow_mut().release_snapshot(self.snapshot);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::types::{
+        cache::{CacheCapacity, CacheConfig},
+        cache_policy::Replacement,
+        file_store::Durability,
+        test_util::{check_against_model, Model, TempFile},
+        tree::Tree,
+    };
+
+    #[test]
+    fn snapshots_are_isolated_from_later_writes() {
+        for durability in [Durability::WriteAheadLog, Durability::ShadowPaging] {
+            let file = TempFile::new("snapshots");
+            let mut tree = Tree::<u32, Vec<u8>>::with_durability(
+                file.path(),
+                128,
+                durability,
+                CacheConfig::default(),
+            )
+            .unwrap();
+            let mut source = random::default(3);
+            let mut model = Model::new();
+
+            let mut snapshots = vec![];
+            for _ in 0..4 {
+                check_against_model(&mut tree, &mut model, &mut source, 300);
+                snapshots.push((tree.snapshot().unwrap(), model.clone()));
+            }
+            check_against_model(&mut tree, &mut model, &mut source, 300);
+
+            // releasing a snapshot in the middle must not free blocks the others still read
+            snapshots.remove(1);
+            check_against_model(&mut tree, &mut model, &mut source, 300);
+
+            for (snapshot, expected) in &snapshots {
+                let report = snapshot.verify();
+                assert!(report.is_ok(), "{durability:?}: {report}");
+
+                let entries: Model = snapshot.iter().collect::<Result<_, _>>().unwrap();
+                assert_eq!(&entries, expected, "{durability:?}");
+                for key in 0..400 {
+                    assert_eq!(
+                        snapshot.search(&key).unwrap().as_ref(),
+                        expected.get(&key),
+                        "{durability:?}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn snapshots_cache_their_nodes_like_the_tree() {
+        let file = TempFile::new("snapshot-cache");
+        let cache = CacheConfig {
+            replacement: Replacement::Lru,
+            capacity: CacheCapacity::Nodes(3),
+        };
+        let mut tree = Tree::<u32, u32>::new(file.path(), 128, cache).unwrap();
+        for key in 0..300 {
+            tree.insert(key, key).unwrap();
+        }
+
+        let snapshot = tree.snapshot().unwrap();
+        assert_eq!(snapshot.iter().count(), 300);
+        assert_eq!(snapshot.store.borrow().cache.capacity(), 3);
+        assert!(snapshot.store.borrow().cache.len() <= 3);
+
+        // and follow it when it is resized
+        tree.set_cache_capacity(CacheCapacity::Bytes(10 * 128))
+            .unwrap();
+        assert_eq!(tree.snapshot().unwrap().store.borrow().cache.capacity(), 10);
+    }
+}
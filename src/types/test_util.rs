@@ -0,0 +1,145 @@
+use std::{
+    collections::BTreeMap,
+    env, fs, process,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use random::Source;
+
+use super::{
+    node::{SearchKey, Value},
+    tree::Tree,
+    wal::Wal,
+};
+
+/// a tree file in the temporary directory, removed together with its log once dropped
+pub struct TempFile {
+    path: String,
+}
+
+impl TempFile {
+    pub fn new(name: &str) -> Self {
+        // tests run in parallel, so every file gets its own name
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let file = format!(
+            "bplus-tree-{}-{}-{name}.db",
+            process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        );
+
+        TempFile {
+            path: env::temp_dir().join(file).to_string_lossy().into_owned(),
+        }
+    }
+
+    pub fn path(&self) -> String {
+        self.path.clone()
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+        let _ = fs::remove_file(Wal::path(&self.path));
+    }
+}
+
+pub type Model<K = u32> = BTreeMap<K, Vec<u8>>;
+
+/// Keys the model test can be run with, made from the numbers it picks. Different numbers have to
+/// make different keys, in the same order.
+pub trait ModelKey: SearchKey + 'static {
+    fn from_number(number: u32) -> Self;
+}
+
+impl ModelKey for u32 {
+    fn from_number(number: u32) -> Self {
+        number
+    }
+}
+
+impl ModelKey for i64 {
+    /// half of the keys are negative
+    fn from_number(number: u32) -> Self {
+        i64::from(number) - 200
+    }
+}
+
+impl ModelKey for (u32, u32) {
+    fn from_number(number: u32) -> Self {
+        (number / 20, number % 20)
+    }
+}
+
+impl ModelKey for [u8; 4] {
+    /// big endian, so the arrays sort like the numbers
+    fn from_number(number: u32) -> Self {
+        number.to_be_bytes()
+    }
+}
+
+impl ModelKey for String {
+    /// every fourth key is more than three times as long as the others, so separators differ a lot
+    /// in size
+    fn from_number(number: u32) -> Self {
+        let padding = if number % 4 == 3 { 10 } else { 0 };
+        format!("{number:04}{}", "-".repeat(padding))
+    }
+}
+
+/// checks that the tree is valid and holds exactly the entries of the model
+pub fn assert_matches_model<K, T>(tree: &Tree<K, T>, model: &BTreeMap<K, T>)
+where
+    K: SearchKey + 'static,
+    T: Value + PartialEq + 'static,
+{
+    let report = tree.verify().unwrap();
+    assert!(report.is_ok(), "{report}");
+
+    let entries: Vec<(K, T)> = tree.iter().collect::<Result<_, _>>().unwrap();
+    let expected: Vec<(K, T)> = model.clone().into_iter().collect();
+    assert_eq!(entries, expected);
+}
+
+/// runs random insertions, upserts and removals against the tree and the model, checking that the
+/// tree stays valid and holds the same entries. The tree grows during the first half and shrinks
+/// during the second one.
+pub fn check_against_model<K: ModelKey>(
+    tree: &mut Tree<K, Vec<u8>>,
+    model: &mut Model<K>,
+    source: &mut impl Source,
+    operations: usize,
+) {
+    for step in 0..operations {
+        let number = (source.read_u64() % 400) as u32;
+        let key = K::from_number(number);
+        // a few values are large enough to be moved to overflow blocks
+        let len = match source.read_u64() % 20 {
+            0 => 300,
+            len => len as usize * 3,
+        };
+        let value = vec![number as u8; len];
+
+        let remove = match source.read_u64() % 3 {
+            0 => step < operations / 2,
+            _ => step >= operations / 2,
+        };
+        if remove {
+            assert_eq!(tree.remove(&key).unwrap(), model.remove(&key));
+        } else if source.read_u64().is_multiple_of(2) {
+            assert_eq!(
+                tree.upsert(key.clone(), value.clone()).unwrap(),
+                model.insert(key, value)
+            );
+        } else {
+            let inserted = tree.insert_if_absent(key.clone(), value.clone()).unwrap();
+            assert_eq!(inserted, !model.contains_key(&key));
+            model.entry(key).or_insert(value);
+        }
+
+        if step % 100 == 0 {
+            assert_matches_model(tree, model);
+        }
+    }
+    assert_matches_model(tree, model);
+}
@@ -1,112 +1,948 @@
-use std::{cell::RefCell, fmt::Debug, io, marker::PhantomData, rc::Rc};
-
+use std::{
+    cell::RefCell,
+    error::Error,
+    fmt,
+    io::{self, Write},
+    ops::RangeBounds,
+    rc::Rc,
+};
 
 use super::{
-    file_store::{FileStore, LoadError, Metadata},
-    node::{InsertionResult, Node, NodeIdent, SearchKey},
-    node_store::{NodeStore, NodeStoreError, BLOCK_SIZE},
+    bulk_load::bulk_load,
+    cache::{CacheCapacity, CacheConfig},
+    entry::Entry,
+    file_store::{Durability, FileStore, LoadError, Metadata},
+    node::{
+        InnerNode, InsertionResult, LeafNode, Node, NodeIdent, RemovalResult, SearchKey, Value,
+    },
+    node_store::{valid_block_size, NodeStore, NodeStoreError, MAX_BLOCK_SIZE, MIN_BLOCK_SIZE},
+    range::Range,
+    snapshot::TreeSnapshot,
+    verify::VerifyReport,
 };
 
-pub struct Tree<T: Sized, const S: usize> {
-    store: Rc<RefCell<dyn NodeStore<T, S>>>,
+pub struct Tree<K: SearchKey, T: Value> {
+    store: Rc<RefCell<dyn NodeStore<K, T>>>,
     root: NodeIdent,
+    // root as of the last commit, restored if a transaction fails
+    committed_root: NodeIdent,
+    // operations inside of a transaction are committed together once it ends
+    in_transaction: bool,
+    // how the cache of the store was set up, snapshots set up theirs the same way
+    cache: CacheConfig,
 }
 
-pub enum TreeCreationError {
+/// Errors returned by the operations of a tree
+#[derive(Debug)]
+pub enum TreeError {
+    /// the key is already present and the operation does not replace existing entries
+    DuplicateKey,
+    /// the key does not fit into a node, even if its value is moved to overflow blocks
+    KeyTooLarge,
+    /// the operation would commit part of a transaction, e.g. `Tree::save`
+    InTransaction,
+    /// the entries to bulk load are not sorted by key
+    Unsorted,
+    /// the fill factor to bulk load with is outside of `0.5..=1.0`
+    InvalidFillFactor(f64),
+    /// the block size to create a tree with is not a power of two or outside of
+    /// `MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE`
+    InvalidBlockSize(usize),
+    StoreError(NodeStoreError),
+    LoadError(LoadError),
     IOError(io::Error),
-    ParameterMismatch,
+    /// a block does not hold what the tree expects it to hold
+    Corrupted {
+        block: NodeIdent,
+        reason: String,
+    },
+}
+
+impl fmt::Display for TreeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TreeError::DuplicateKey => write!(f, "the key is already present"),
+            TreeError::KeyTooLarge => write!(f, "the key is too large to be stored"),
+            TreeError::InTransaction => write!(f, "not possible inside of a transaction"),
+            TreeError::Unsorted => write!(f, "the entries are not sorted by key"),
+            TreeError::InvalidFillFactor(fill_factor) => {
+                write!(f, "the fill factor {fill_factor} is not between 0.5 and 1")
+            }
+            TreeError::InvalidBlockSize(block_size) => write!(
+                f,
+                "the block size {block_size} is not a power of two between {MIN_BLOCK_SIZE} and \
+                 {MAX_BLOCK_SIZE}"
+            ),
+            TreeError::StoreError(e) => write!(f, "node store error: {e}"),
+            TreeError::LoadError(e) => write!(f, "unable to load the tree: {e}"),
+            TreeError::IOError(e) => write!(f, "io error: {e}"),
+            TreeError::Corrupted { block, reason } => {
+                write!(f, "block {block} is corrupted: {reason}")
+            }
+        }
+    }
 }
 
-impl<T, const S: usize> Tree<T, S>
+impl Error for TreeError {}
+
+impl From<NodeStoreError> for TreeError {
+    fn from(e: NodeStoreError) -> Self {
+        match e {
+            NodeStoreError::Malformed { block, reason } => TreeError::Corrupted { block, reason },
+            NodeStoreError::Corrupted {
+                block,
+                expected,
+                actual,
+            } => TreeError::Corrupted {
+                block,
+                reason: format!("checksum {actual:08x} does not match {expected:08x}"),
+            },
+            e => TreeError::StoreError(e),
+        }
+    }
+}
+
+impl From<LoadError> for TreeError {
+    fn from(e: LoadError) -> Self {
+        match e {
+            LoadError::IOError(e) => TreeError::IOError(e),
+            LoadError::StoreError(e) => e.into(),
+            e => TreeError::LoadError(e),
+        }
+    }
+}
+
+impl From<io::Error> for TreeError {
+    fn from(e: io::Error) -> Self {
+        TreeError::IOError(e)
+    }
+}
+
+impl<K, T> Tree<K, T>
 where
-    T: Sized + 'static,
-    T: Debug,
+    K: SearchKey + 'static,
+    T: Value + 'static,
 {
-    pub fn insert(&mut self, key: SearchKey, value: NodeIdent) -> () {
-        let res = Node::insert(self.root, key, value, self.store.clone());
-
-        match res {
-            InsertionResult::Ok => (),
-            InsertionResult::NodeOverflow(separator, ident, _) => {
-                let mut separators = [0; S];
-                let mut children = [0; S];
-
-                separators[0] = separator;
-                children[0] = self.root;
-                children[1] = ident;
-
-                let new_root = Node {
-                    size: 1,
-                    separators,
-                    children,
-                    phantom: PhantomData,
-                };
+    /// inserts a new entry, failing if the key is already present
+    pub fn insert(&mut self, key: K, value: T) -> Result<(), TreeError> {
+        self.insert_entry(key, value, false).map(|_| ())
+    }
+
+    /// inserts the entry, replacing the value of an existing entry with the same key. Returns the
+    /// value that was replaced.
+    pub fn upsert(&mut self, key: K, value: T) -> Result<Option<T>, TreeError> {
+        self.insert_entry(key, value, true)
+    }
+
+    /// inserts the entry unless the key is already present. Returns whether it was inserted.
+    pub fn insert_if_absent(&mut self, key: K, value: T) -> Result<bool, TreeError> {
+        match self.insert(key, value) {
+            Ok(()) => Ok(true),
+            Err(TreeError::DuplicateKey) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
 
-                let mut node_store = self.store.borrow_mut();
-                let root_ident_new = node_store.store_node(new_root, false).unwrap();
-                self.root = root_ident_new;
+    /// looks up the entry for the key so it can be inspected and updated in place
+    pub fn entry(&mut self, key: K) -> Result<Entry<'_, K, T>, TreeError> {
+        Entry::new(self, key)
+    }
+
+    /// runs `f` as a single transaction: either all changes it makes are committed or, if it
+    /// returns an error, none of them are. Transactions started inside of `f` become part of this
+    /// one.
+    pub fn transaction<R, F>(&mut self, f: F) -> Result<R, TreeError>
+    where
+        F: FnOnce(&mut Self) -> Result<R, TreeError>,
+    {
+        if self.in_transaction {
+            return f(self);
+        }
+
+        self.in_transaction = true;
+        let result = f(self).and_then(|r| self.commit().map(|_| r));
+        self.in_transaction = false;
+
+        // the changes only exist in the store's cache and uncommitted blocks so far
+        if result.is_err() {
+            self.store.borrow_mut().rollback();
+            self.root = self.committed_root;
+        }
+
+        result
+    }
+
+    fn insert_entry(&mut self, key: K, value: T, replace: bool) -> Result<Option<T>, TreeError> {
+        self.transaction(|tree| tree.insert_uncommitted(key, value, replace))
+    }
+
+    fn insert_uncommitted(
+        &mut self,
+        key: K,
+        value: T,
+        replace: bool,
+    ) -> Result<Option<T>, TreeError> {
+        let old = match Node::insert(self.root, key, value, replace, self.store.clone()) {
+            InsertionResult::Ok(old) => old,
+            // the root is allowed to underflow, but rebalancing its children may have left it
+            // with a single one
+            InsertionResult::NodeUnderflow(old) => {
+                self.shrink_root()?;
+                old
+            }
+            InsertionResult::NodeOverflow(old, separator, ident) => {
+                self.grow_root(separator, ident)?;
+                old
             }
-            InsertionResult::DuplicateKey => println!("Tried to insert duplicate key"),
+            InsertionResult::DuplicateKey => return Err(TreeError::DuplicateKey),
+            InsertionResult::KeyTooLarge => return Err(TreeError::KeyTooLarge),
+            InsertionResult::InsertError(e) => return Err(e.into()),
+        };
 
-            e => {
-                println!("Unable to insert: {e:?}");
+        Ok(old)
+    }
+
+    /// puts a new root above the current one after the root was split
+    fn grow_root(&mut self, separator: K, ident: NodeIdent) -> Result<(), NodeStoreError> {
+        let new_root = InnerNode {
+            separators: vec![separator],
+            children: vec![self.root, ident],
+        };
+
+        let mut node_store = self.store.borrow_mut();
+        let root_ident_new = node_store.store_node(Node::Inner(new_root))?;
+        self.root = root_ident_new;
+
+        Ok(())
+    }
 
-                panic!("Bye");
+    /// removes the key from the tree, returning the value that was stored for it
+    pub fn remove(&mut self, key: &K) -> Result<Option<T>, TreeError> {
+        self.transaction(|tree| tree.remove_uncommitted(key))
+    }
+
+    fn remove_uncommitted(&mut self, key: &K) -> Result<Option<T>, TreeError> {
+        let value = match Node::remove(self.root, key, self.store.clone()) {
+            // the root is allowed to underflow
+            RemovalResult::Ok(value) | RemovalResult::NodeUnderflow(value) => value,
+            RemovalResult::NodeOverflow(value, separator, ident) => {
+                self.grow_root(separator, ident)?;
+                return Ok(Some(value));
             }
+            RemovalResult::NotFound => return Ok(None),
+            RemovalResult::RemoveError(e) => return Err(e.into()),
+        };
+
+        self.shrink_root()?;
+
+        Ok(Some(value))
+    }
+
+    /// an inner root that lost its last separator only has a single subtree left, which becomes
+    /// the new root
+    fn shrink_root(&mut self) -> Result<(), NodeStoreError> {
+        let mut node_store = self.store.borrow_mut();
+        let new_root = match &*node_store.read_node(self.root)? {
+            Node::Inner(root) if root.separators.is_empty() => Some(root.children[0]),
+            _ => None,
+        };
+
+        if let Some(new_root) = new_root {
+            let old_root = self.root;
+            self.root = new_root;
+            node_store.free_node(old_root)?;
+        }
+
+        Ok(())
+    }
+
+    /// writes all cached nodes back to the file. This commits, so it fails inside of a
+    /// transaction.
+    pub fn save(&mut self) -> Result<(), TreeError> {
+        if self.in_transaction {
+            return Err(TreeError::InTransaction);
         }
+
+        self.store.borrow_mut().flush()?;
+        self.commit()?;
+        self.store.borrow_mut().checkpoint()?;
+
+        Ok(())
     }
 
-    pub fn save(&mut self) -> () {
+    /// makes the changes of the last operation durable, together with the metadata describing
+    /// the tree after it. Operations that didn't change anything, e.g. removing a missing key,
+    /// don't write anything.
+    fn commit(&mut self) -> Result<(), TreeError> {
         let mut node_store = self.store.borrow_mut();
-        node_store.flush();
+        if self.root == self.committed_root && !node_store.has_changes() {
+            return Ok(());
+        }
+
         let node_ctr = node_store.node_ctr();
+        let free_list_head = node_store.free_list_head();
+        let block_size = node_store.block_size();
         node_store.set_metadata(Metadata {
-            fanout: S,
             root_node: self.root,
-            block_size: BLOCK_SIZE,
+            block_size,
             node_ctr,
+            free_list_head,
             node_ident_size: size_of::<NodeIdent>(),
-            search_key_size: size_of::<SearchKey>(),
-        });
+            search_key_size: K::FIXED_SIZE.unwrap_or(0),
+            page_table: 0,
+        })?;
+        node_store.commit()?;
+        self.committed_root = self.root;
+
+        Ok(())
     }
 
-    pub fn load(path: String) -> Result<Self, LoadError> {
-        let (store, root) = match FileStore::<T, S>::load(path) {
-            Ok(s) => s,
-            Err(e) => return Err(e),
-        };
+    /// opens an existing tree, `cache` sets up the cache of its nodes
+    pub fn load(path: String, cache: CacheConfig) -> Result<Self, TreeError> {
+        let (store, root) = FileStore::<K, T>::load(path, cache)?;
 
         Ok(Tree {
             root,
+            committed_root: root,
             store: Rc::new(RefCell::new(store)),
+            in_transaction: false,
+            cache,
         })
     }
 
-    pub fn new(path: String) -> Result<Self, io::Error> {
-        let mut store = FileStore::<T, S>::new(path)?;
+    /// creates a new tree stored in blocks of `block_size` bytes, e.g. `DEFAULT_BLOCK_SIZE`. The
+    /// block size is kept in the metadata, so loading the tree again doesn't need it.
+    pub fn new(path: String, block_size: usize, cache: CacheConfig) -> Result<Self, TreeError> {
+        Self::with_durability(path, block_size, Durability::WriteAheadLog, cache)
+    }
+
+    /// creates a new tree, choosing how its commits are made durable. Loading the tree again keeps
+    /// the choice.
+    pub fn with_durability(
+        path: String,
+        block_size: usize,
+        durability: Durability,
+        cache: CacheConfig,
+    ) -> Result<Self, TreeError> {
+        if !valid_block_size(block_size) {
+            return Err(TreeError::InvalidBlockSize(block_size));
+        }
 
-        let leaf = Node {
-            children: [0; S],
-            separators: [0; S],
-            size: 0,
-            phantom: PhantomData::<T>,
+        let mut store = FileStore::<K, T>::new(path, block_size, durability, cache)?;
+
+        let root = store.store_node(Node::Leaf(LeafNode::new()))?;
+
+        // nothing is committed yet, not even the metadata
+        let mut tree = Self {
+            root,
+            committed_root: 0,
+            store: Rc::new(RefCell::new(store)),
+            in_transaction: false,
+            cache,
         };
+        tree.commit()?;
+
+        Ok(tree)
+    }
+
+    /// creates a new tree holding the entries, which have to be sorted by key without duplicates.
+    /// Leaves are built from left to right and filled up to `fill_factor` of their block, leaving
+    /// room for later insertions, then the inner levels are built on top of them. A fill factor
+    /// below 0.5 would leave nodes underfull. The blocks are written through the store in the
+    /// order they are allocated, so the leaves end up in ascending blocks, and committed in
+    /// batches. The tree only becomes visible once the metadata pointing at its root is committed
+    /// last, so the file holds no tree if loading fails. `cache` sets up the cache of the returned
+    /// tree.
+    pub fn bulk_load<I>(
+        path: String,
+        block_size: usize,
+        entries: I,
+        fill_factor: f64,
+        cache: CacheConfig,
+    ) -> Result<Self, TreeError>
+    where
+        I: IntoIterator<Item = (K, T)>,
+    {
+        if !(0.5..=1.0).contains(&fill_factor) {
+            return Err(TreeError::InvalidFillFactor(fill_factor));
+        }
+        if !valid_block_size(block_size) {
+            return Err(TreeError::InvalidBlockSize(block_size));
+        }
 
-        let root = store.store_node(leaf, true).unwrap();
+        let mut store = FileStore::<K, T>::new(path, block_size, Durability::WriteAheadLog, cache)?;
+        let root = bulk_load(&mut store, entries, fill_factor)?;
 
-        Ok(Self {
+        let mut tree = Self {
             root,
+            committed_root: 0,
             store: Rc::new(RefCell::new(store)),
+            in_transaction: false,
+            cache,
+        };
+        // publishes the nodes committed so far together with the last ones
+        tree.commit()?;
+
+        Ok(tree)
+    }
+
+    /// changes how many nodes are cached. If the cache shrinks, the nodes that no longer fit are
+    /// written back.
+    pub fn set_cache_capacity(&mut self, capacity: CacheCapacity) -> Result<(), TreeError> {
+        self.store.borrow_mut().set_cache_capacity(capacity)?;
+        self.cache.capacity = capacity;
+
+        Ok(())
+    }
+
+    /// writes the nodes of the tree in the DOT format
+    pub fn print_graphviz(&self, out: &mut impl Write) -> Result<(), TreeError> {
+        writeln!(out, "digraph G {{")?;
+        self.store.borrow_mut().print_stored_nodes(self.root, out)?;
+        writeln!(out, "}}")?;
+
+        Ok(())
+    }
+
+    pub fn search(&self, key: &K) -> Result<Option<T>, TreeError> {
+        Ok(Node::search(self.root, key, self.store.clone())?)
+    }
+
+    /// iterates over all entries with keys inside the given range in key order
+    pub fn range<R>(&self, range: R) -> Range<'_, K, T>
+    where
+        R: RangeBounds<K>,
+    {
+        let front = range.start_bound().cloned();
+        let back = range.end_bound().cloned();
+
+        Range::new(&*self.store, self.root, front, back)
+    }
+
+    /// iterates over all entries in key order
+    pub fn iter(&self) -> Range<'_, K, T> {
+        self.range(..)
+    }
+
+    /// takes a read-only snapshot of the tree as of the last commit, which is not affected by any
+    /// later changes. Changes of a transaction in progress are not part of it. The snapshot caches
+    /// its nodes like the tree does.
+    pub fn snapshot(&self) -> Result<TreeSnapshot<K, T>, TreeError> {
+        TreeSnapshot::new(self.store.clone(), self.cache)
+    }
+
+    /// checks the tree as of the last commit: the order of the keys and their bounds, the depth,
+    /// fill and links of the nodes, and whether every block is used exactly once
+    pub fn verify(&self) -> Result<VerifyReport, TreeError> {
+        Ok(self.snapshot()?.verify())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, fs, ops::Bound};
+
+    use super::*;
+    use crate::types::{
+        cache_policy::Replacement,
+        node::LeafValue,
+        test_util::{assert_matches_model, check_against_model, Model, ModelKey, TempFile},
+        wal::Wal,
+    };
+
+    #[test]
+    fn operations_match_a_btree_map() {
+        fn check<K: ModelKey>(durability: Durability, replacement: Replacement, seed: u64) {
+            // a tiny cache evicts nodes all the time, even those in the middle of a split
+            let cache = CacheConfig {
+                replacement,
+                capacity: CacheCapacity::Nodes(3),
+            };
+            let file = TempFile::new("model");
+            let mut tree =
+                Tree::<K, Vec<u8>>::with_durability(file.path(), 128, durability, cache).unwrap();
+            let mut model = Model::new();
+            check_against_model(&mut tree, &mut model, &mut random::default(seed), 2000);
+
+            // every operation was committed, so the file holds them without being saved
+            drop(tree);
+            let tree = Tree::<K, Vec<u8>>::load(file.path(), cache).unwrap();
+            assert_matches_model(&tree, &model);
+        }
+
+        let mut seed = 0;
+        for durability in [Durability::WriteAheadLog, Durability::ShadowPaging] {
+            for replacement in [
+                Replacement::Lru,
+                Replacement::Clock,
+                Replacement::TwoQueue,
+                Replacement::Arc,
+            ] {
+                seed += 1;
+                check::<u32>(durability, replacement, seed);
+                check::<i64>(durability, replacement, seed);
+                check::<(u32, u32)>(durability, replacement, seed);
+                check::<[u8; 4]>(durability, replacement, seed);
+                check::<String>(durability, replacement, seed);
+            }
+        }
+    }
+
+    #[test]
+    fn negative_keys_are_iterated_in_order() {
+        let file = TempFile::new("negative");
+        let mut tree = Tree::<i64, i64>::new(file.path(), 128, CacheConfig::default()).unwrap();
+        // zero was once used as the marker of empty slots, so it is inserted with a zero value
+        let keys = [5, -3, 0, i64::MAX, -1, i64::MIN, 42, -42];
+        for key in keys {
+            tree.insert(key, key).unwrap();
+        }
+
+        let mut expected = keys.to_vec();
+        expected.sort();
+        let entries: Vec<(i64, i64)> = tree.iter().collect::<Result<_, _>>().unwrap();
+        assert_eq!(
+            entries,
+            expected.iter().map(|&key| (key, key)).collect::<Vec<_>>()
+        );
+        assert_eq!(tree.search(&0).unwrap(), Some(0));
+    }
+
+    #[test]
+    fn failed_transactions_roll_back() {
+        for durability in [Durability::WriteAheadLog, Durability::ShadowPaging] {
+            let file = TempFile::new("rollback");
+            let mut tree = Tree::<u32, Vec<u8>>::with_durability(
+                file.path(),
+                128,
+                durability,
+                CacheConfig::default(),
+            )
+            .unwrap();
+            let mut model = Model::new();
+            check_against_model(&mut tree, &mut model, &mut random::default(5), 600);
+
+            // enough changes to split, merge and free nodes before the duplicate fails
+            let result = tree.transaction(|tree| {
+                for key in 400..700 {
+                    tree.upsert(key, vec![1; 40])?;
+                }
+                for key in 0..300 {
+                    tree.remove(&key)?;
+                }
+                tree.insert(400, vec![])
+            });
+            assert!(matches!(result, Err(TreeError::DuplicateKey)));
+            assert_matches_model(&tree, &model);
+
+            // the blocks the failed transaction allocated are unused again
+            for key in 400..700 {
+                tree.insert(key, vec![2; 40]).unwrap();
+                model.insert(key, vec![2; 40]);
+            }
+            assert_matches_model(&tree, &model);
+
+            drop(tree);
+            let tree = Tree::<u32, Vec<u8>>::load(file.path(), CacheConfig::default()).unwrap();
+            assert_matches_model(&tree, &model);
+        }
+    }
+
+    #[test]
+    fn removing_missing_keys_writes_nothing() {
+        for durability in [Durability::WriteAheadLog, Durability::ShadowPaging] {
+            let file = TempFile::new("missing");
+            let mut tree = Tree::<u32, u32>::with_durability(
+                file.path(),
+                128,
+                durability,
+                CacheConfig::default(),
+            )
+            .unwrap();
+            for key in 0..200 {
+                tree.insert(key, key).unwrap();
+            }
+
+            // shadow paged files have no log, but publish every commit in the tree file
+            let log_size = || fs::metadata(Wal::path(&file.path())).map_or(0, |log| log.len());
+            let (log, contents) = (log_size(), fs::read(file.path()).unwrap());
+            for key in 200..300 {
+                assert_eq!(tree.remove(&key).unwrap(), None);
+            }
+            assert_eq!(log_size(), log, "{durability:?}");
+            assert!(fs::read(file.path()).unwrap() == contents, "{durability:?}");
+        }
+    }
+
+    #[test]
+    fn errors_are_displayed_without_debug_formatting() {
+        assert_eq!(
+            TreeError::from(NodeStoreError::ReadOnly).to_string(),
+            "node store error: the store is read only"
+        );
+        assert_eq!(
+            TreeError::from(LoadError::ParameterMismatch).to_string(),
+            "unable to load the tree: the file was written with different parameters"
+        );
+    }
+
+    #[test]
+    fn transactions_cant_be_saved() {
+        let file = TempFile::new("save-in-transaction");
+        let mut tree = Tree::<u32, u32>::new(file.path(), 128, CacheConfig::default()).unwrap();
+        for key in 0..100 {
+            tree.insert(key, key).unwrap();
+        }
+
+        let result = tree.transaction(|tree| {
+            for key in 100..200 {
+                tree.insert(key, key)?;
+            }
+            assert!(matches!(tree.save(), Err(TreeError::InTransaction)));
+            for key in 0..50 {
+                tree.remove(&key)?;
+            }
+            Err::<(), _>(TreeError::DuplicateKey)
+        });
+        assert!(result.is_err());
+
+        let model = (0..100).map(|key| (key, key)).collect();
+        assert_matches_model(&tree, &model);
+
+        tree.save().unwrap();
+        let tree = Tree::<u32, u32>::load(file.path(), CacheConfig::default()).unwrap();
+        assert_matches_model(&tree, &model);
+    }
+
+    #[test]
+    fn cache_shrinks_inside_of_transactions() {
+        let file = TempFile::new("resize-transaction");
+        let mut tree = Tree::<u32, u32>::new(file.path(), 128, CacheConfig::default()).unwrap();
+
+        // the dirty nodes paged out by the shrinking cache are part of the commit
+        tree.transaction(|tree| {
+            (0..300).try_for_each(|key| tree.insert(key, key))?;
+            tree.set_cache_capacity(CacheCapacity::Bytes(3 * 128))?;
+            (300..400).try_for_each(|key| tree.insert(key, key))
         })
+        .unwrap();
+        let model = (0..400).map(|key| (key, key)).collect();
+        assert_matches_model(&tree, &model);
+
+        // and thrown away together with the rest of a failed transaction
+        let result = tree.transaction(|tree| {
+            (400..700).try_for_each(|key| tree.insert(key, key))?;
+            tree.set_cache_capacity(CacheCapacity::Nodes(1))?;
+            tree.insert(0, 0)
+        });
+        assert!(matches!(result, Err(TreeError::DuplicateKey)));
+        assert_matches_model(&tree, &model);
+
+        drop(tree);
+        let tree = Tree::<u32, u32>::load(file.path(), CacheConfig::default()).unwrap();
+        assert_matches_model(&tree, &model);
+    }
+
+    #[test]
+    fn shrinking_values_rebalances_leaves() {
+        let file = TempFile::new("shrink");
+        let mut tree = Tree::<u32, Vec<u8>>::new(file.path(), 128, CacheConfig::default()).unwrap();
+
+        // scattered keys leave some leaves with only a few entries after splitting
+        for i in 0..300 {
+            let key = i * 7919 % 300;
+            tree.insert(key, vec![key as u8; 19]).unwrap();
+        }
+        // replacing every value with a smaller one leaves the leaves without enough entries to be
+        // a quarter full
+        for key in 0..300 {
+            assert_eq!(tree.upsert(key, vec![]).unwrap(), Some(vec![key as u8; 19]));
+            let report = tree.verify().unwrap();
+            assert!(report.is_ok(), "after shrinking {key}: {report}");
+        }
+
+        let entries: Vec<(u32, Vec<u8>)> = tree.iter().collect::<Result<_, _>>().unwrap();
+        assert_eq!(
+            entries,
+            (0..300).map(|key| (key, vec![])).collect::<Vec<_>>()
+        );
     }
 
-    pub fn print_graphviz(&self) {
-        println!("digraph G {{");
-        self.store.borrow_mut().print_stored_nodes(self.root);
-        println!("}}");
+    #[test]
+    fn snapshot_ranges_ignore_later_writes() {
+        let file = TempFile::new("scan");
+        let mut tree = Tree::<u32, u32>::new(file.path(), 128, CacheConfig::default()).unwrap();
+        for key in 0..300 {
+            tree.insert(key, key).unwrap();
+        }
+
+        // the leaves the scan follows are freed and reused by the writes in between
+        let snapshot = tree.snapshot().unwrap();
+        let mut range = snapshot.iter();
+        assert_eq!(range.next().unwrap().unwrap(), (0, 0));
+        for key in 0..200 {
+            tree.remove(&key).unwrap();
+        }
+        for key in 1000..1300 {
+            tree.insert(key, key).unwrap();
+        }
+
+        let rest: Vec<(u32, u32)> = range.collect::<Result<_, _>>().unwrap();
+        assert_eq!(rest, (1..300).map(|key| (key, key)).collect::<Vec<_>>());
+
+        let current: Vec<u32> = tree.iter().map(|entry| entry.unwrap().0).collect();
+        assert_eq!(current, (200..300).chain(1000..1300).collect::<Vec<_>>());
     }
 
-    pub fn search(&self, key: SearchKey) -> Result<Option<NodeIdent>, NodeStoreError> {
-        Node::search(self.root, key, self.store.clone())
+    #[test]
+    fn larger_separators_split_their_node() {
+        let file = TempFile::new("separators");
+        let mut tree = Tree::<String, u32>::new(file.path(), 128, CacheConfig::default()).unwrap();
+        let short = |number: usize| format!("{number:04}");
+        let long = |number: usize| format!("{number:04}{}", "-".repeat(14));
+        let leaf = |keys: &[String]| LeafNode::<String, u32> {
+            keys: keys.to_vec(),
+            values: keys.iter().map(|_| LeafValue::Inline(0)).collect(),
+            next: 0,
+            prev: 0,
+        };
+
+        // a root holding as many short separators as fit, so any longer one makes it overflow
+        let mut root = InnerNode::new();
+        while root.fits(128) {
+            root.separators
+                .push(short(100 * (root.separators.len() + 1)));
+        }
+        root.separators.pop();
+
+        // the first leaf underflows once it loses a key and its sibling is too full to merge with
+        // it, so they share their entries and the separator becomes one of the long keys
+        let mut leaves = vec![vec![short(0), short(1)], vec![]];
+        loop {
+            let mut keys = leaves[1].clone();
+            keys.push(long(100 + keys.len()));
+            if !leaf(&keys).fits(128) {
+                break;
+            }
+            leaves[1] = keys;
+        }
+        for leaf in 2..=root.separators.len() {
+            leaves.push((0..3).map(|key| short(100 * leaf + key)).collect());
+        }
+
+        {
+            let mut store = tree.store.borrow_mut();
+            for keys in &leaves {
+                root.children
+                    .push(store.store_node(Node::Leaf(leaf(keys))).unwrap());
+            }
+            for (index, &child) in root.children.iter().enumerate() {
+                let mut leaf = store.get_leaf(child).unwrap();
+                leaf.prev = if index > 0 {
+                    root.children[index - 1]
+                } else {
+                    0
+                };
+                leaf.next = root.children.get(index + 1).copied().unwrap_or(0);
+            }
+            *store.get_node(tree.root).unwrap() = Node::Inner(root);
+        }
+        tree.commit().unwrap();
+        assert_eq!(tree.verify().unwrap().depth, 2);
+
+        assert_eq!(tree.remove(&short(0)).unwrap(), Some(0));
+        let mut model: BTreeMap<String, u32> =
+            leaves.into_iter().flatten().map(|key| (key, 0)).collect();
+        model.remove(&short(0));
+
+        // the root was split, so the tree grew while removing a key
+        assert_eq!(tree.verify().unwrap().depth, 3);
+        assert_matches_model(&tree, &model);
+    }
+
+    #[test]
+    fn removals_rebalance_and_collapse_the_tree() {
+        let file = TempFile::new("remove");
+        let mut tree = Tree::<u32, u32>::new(file.path(), 128, CacheConfig::default()).unwrap();
+        for key in 0..1000 {
+            tree.insert(key, key).unwrap();
+        }
+
+        // the front half first, then every other key of the rest, so leaves merge with and borrow
+        // from both of their siblings
+        let keys = (0..500)
+            .chain((500..1000).step_by(2))
+            .chain((501..1000).step_by(2));
+        for (step, key) in keys.enumerate() {
+            assert_eq!(tree.remove(&key).unwrap(), Some(key));
+            assert_eq!(tree.remove(&key).unwrap(), None);
+            if step % 50 == 0 {
+                let report = tree.verify().unwrap();
+                assert!(report.is_ok(), "after removing {key}: {report}");
+            }
+        }
+
+        // the inner nodes were freed until only the root leaf was left
+        let report = tree.verify().unwrap();
+        assert!(report.is_ok(), "{report}");
+        assert_eq!((report.depth, report.leaves, report.entries), (1, 1, 0));
+        tree.insert(5, 5).unwrap();
+        assert_eq!(tree.search(&5).unwrap(), Some(5));
+    }
+
+    #[test]
+    fn freed_blocks_are_reused() {
+        let file = TempFile::new("reuse");
+        let mut tree = Tree::<u32, Vec<u8>>::new(file.path(), 128, CacheConfig::default()).unwrap();
+        // every tenth value is moved to overflow blocks, which are freed as well
+        let value = |key: u32| vec![key as u8; if key.is_multiple_of(10) { 300 } else { 20 }];
+        let refill = |tree: &mut Tree<u32, Vec<u8>>| {
+            for key in 0..1000 {
+                assert_eq!(tree.remove(&key).unwrap(), Some(value(key)));
+            }
+            for key in 0..1000 {
+                tree.insert(key, value(key)).unwrap();
+            }
+            tree.save().unwrap();
+        };
+
+        for key in 0..1000 {
+            tree.insert(key, value(key)).unwrap();
+        }
+        tree.save().unwrap();
+        let blocks = tree.store.borrow().node_ctr();
+        let len = fs::metadata(file.path()).unwrap().len();
+
+        refill(&mut tree);
+        assert_eq!(tree.store.borrow().node_ctr(), blocks);
+        assert_eq!(fs::metadata(file.path()).unwrap().len(), len);
+
+        // the free list is saved with the tree
+        for key in 0..500 {
+            tree.remove(&key).unwrap();
+        }
+        tree.save().unwrap();
+        drop(tree);
+        let mut tree = Tree::<u32, Vec<u8>>::load(file.path(), CacheConfig::default()).unwrap();
+        for key in 0..500 {
+            tree.insert(key, value(key)).unwrap();
+        }
+        refill(&mut tree);
+        assert_eq!(tree.store.borrow().node_ctr(), blocks);
+        assert_eq!(fs::metadata(file.path()).unwrap().len(), len);
+    }
+
+    #[test]
+    fn entries_match_a_btree_map() {
+        let file = TempFile::new("entries");
+        let mut tree = Tree::<u32, Vec<u8>>::new(file.path(), 128, CacheConfig::default()).unwrap();
+        let mut model = Model::new();
+
+        // vacant entries take the default, occupied ones keep their value
+        for key in 0..300 {
+            let value = tree.entry(key).unwrap().or_insert(vec![1; 4]).unwrap();
+            assert_eq!(value, vec![1; 4]);
+            model.insert(key, value);
+        }
+        for key in 0..300 {
+            let value = tree.entry(key).unwrap().or_insert_with(|| unreachable!());
+            assert_eq!(value.unwrap(), model[&key]);
+        }
+        assert_matches_model(&tree, &model);
+
+        // growing the values splits the leaves, shrinking them again makes the leaves underflow
+        for len in [40, 1] {
+            for key in 0..300 {
+                let entry = tree.entry(key).unwrap();
+                let entry = entry.and_modify(|value| value.resize(len, 2)).unwrap();
+                assert!(matches!(entry, Entry::Occupied(entry) if entry.get().len() == len));
+                model.get_mut(&key).unwrap().resize(len, 2);
+            }
+            assert_matches_model(&tree, &model);
+        }
+
+        for key in (0..300).step_by(3) {
+            let Entry::Occupied(entry) = tree.entry(key).unwrap() else {
+                panic!("{key} is missing");
+            };
+            assert_eq!(entry.remove().unwrap(), model.remove(&key).unwrap());
+        }
+        for key in (0..600).step_by(2) {
+            match tree.entry(key).unwrap() {
+                Entry::Occupied(mut entry) => {
+                    let old = entry.insert(vec![3]).unwrap();
+                    assert_eq!(Some(old), model.insert(key, vec![3]));
+                }
+                Entry::Vacant(entry) => {
+                    assert_eq!(entry.insert(vec![4]).unwrap(), vec![4]);
+                    assert_eq!(model.insert(key, vec![4]), None);
+                }
+            }
+        }
+        let entry = tree.entry(1000).unwrap().and_modify(|_| unreachable!());
+        assert!(matches!(entry.unwrap(), Entry::Vacant(_)));
+        assert_matches_model(&tree, &model);
+
+        tree.save().unwrap();
+        let tree = Tree::<u32, Vec<u8>>::load(file.path(), CacheConfig::default()).unwrap();
+        assert_matches_model(&tree, &model);
+    }
+
+    #[test]
+    fn ranges_match_a_btree_map() {
+        let file = TempFile::new("ranges");
+        let mut tree = Tree::<u32, u32>::new(file.path(), 128, CacheConfig::default()).unwrap();
+        let model: BTreeMap<u32, u32> = (0..600).step_by(2).map(|key| (key, key)).collect();
+        for (&key, &value) in &model {
+            tree.insert(key, value).unwrap();
+        }
+        assert!(tree.verify().unwrap().leaves > 10);
+
+        // bounds on both ends of the tree, on keys, between keys and at the edges of leaves
+        let mut bounds = vec![Bound::Unbounded];
+        for key in [0, 1, 2, 151, 300, 301, 598, 599, 700] {
+            bounds.extend([Bound::Included(key), Bound::Excluded(key)]);
+        }
+        for &front in &bounds {
+            for &back in &bounds {
+                let range = (front, back);
+                // `BTreeMap::range` panics on inverted ranges, which are empty for the tree
+                let inverted = match range {
+                    (Bound::Included(start), Bound::Included(end)) => start > end,
+                    (Bound::Included(start) | Bound::Excluded(start), Bound::Excluded(end))
+                    | (Bound::Excluded(start), Bound::Included(end)) => start >= end,
+                    _ => false,
+                };
+                let expected: Vec<(u32, u32)> = if inverted {
+                    vec![]
+                } else {
+                    model
+                        .range(range)
+                        .map(|(&key, &value)| (key, value))
+                        .collect()
+                };
+
+                let forward: Vec<(u32, u32)> = tree.range(range).collect::<Result<_, _>>().unwrap();
+                assert_eq!(forward, expected, "{range:?}");
+                let mut backward: Vec<(u32, u32)> =
+                    tree.range(range).rev().collect::<Result<_, _>>().unwrap();
+                backward.reverse();
+                assert_eq!(backward, expected, "{range:?}");
+
+                // both ends meet without returning an entry twice
+                let mut entries = tree.range(range);
+                let (mut front_half, mut back_half) = (vec![], vec![]);
+                while let Some(entry) = entries.next() {
+                    front_half.push(entry.unwrap());
+                    match entries.next_back() {
+                        Some(entry) => back_half.push(entry.unwrap()),
+                        None => break,
+                    }
+                }
+                assert!(entries.next().is_none() && entries.next_back().is_none());
+                front_half.extend(back_half.into_iter().rev());
+                assert_eq!(front_half, expected, "{range:?}");
+            }
+        }
     }
 }
@@ -0,0 +1,593 @@
+use std::{collections::BTreeSet, fmt};
+
+use super::{
+    file_store::{overflow_link, overflow_payload_size, FreeBlock, Metadata},
+    node::{LeafValue, Node, NodeIdent, SearchKey, Value},
+    node_store::{Block, NodeStoreError},
+};
+
+/// A problem found while verifying a tree. References from the metadata (to the root and the
+/// first free block) use 0 as the block they come from.
+#[derive(Debug)]
+pub enum Violation {
+    /// the block can't be read or does not hold what the reference to it expects
+    UnreadableBlock {
+        block: NodeIdent,
+        error: NodeStoreError,
+    },
+    /// a reference to a block that was never allocated
+    DanglingReference { from: NodeIdent, block: NodeIdent },
+    /// a block is referenced more than once, by child links, overflow chains or the free list
+    DuplicateReference { from: NodeIdent, block: NodeIdent },
+    /// the key at `index` is not larger than the one before it
+    UnorderedKeys { node: NodeIdent, index: usize },
+    /// the key at `index` lies outside of the range the separators of the parent allow
+    KeyOutOfBounds { node: NodeIdent, index: usize },
+    /// an inner node needs exactly one more child than it has separators
+    ChildCountMismatch {
+        node: NodeIdent,
+        separators: usize,
+        children: usize,
+    },
+    /// the leaf is not at the same depth as the first leaf
+    UnevenDepth {
+        leaf: NodeIdent,
+        depth: usize,
+        expected: usize,
+    },
+    /// a node other than the root uses less than a quarter of its block
+    Underfull { node: NodeIdent },
+    /// the links of the leaf don't point at its neighbours in key order
+    BrokenLeafLink {
+        leaf: NodeIdent,
+        prev: NodeIdent,
+        next: NodeIdent,
+        expected_prev: NodeIdent,
+        expected_next: NodeIdent,
+    },
+    /// the overflow chain of the value at `index` does not end after the blocks the value needs
+    BrokenOverflowChain { leaf: NodeIdent, index: usize },
+    /// a block below the node counter is neither used by the tree nor in the free list
+    UnreachableBlock { block: NodeIdent },
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::UnreadableBlock { block, error } => {
+                write!(f, "block {block} can't be read: {error}")
+            }
+            Violation::DanglingReference { from, block } => {
+                write!(f, "block {from} references block {block}, which was never allocated")
+            }
+            Violation::DuplicateReference { from, block } => {
+                write!(f, "block {from} references block {block}, which is already used")
+            }
+            Violation::UnorderedKeys { node, index } => {
+                write!(f, "key {index} of node {node} is not larger than the one before")
+            }
+            Violation::KeyOutOfBounds { node, index } => {
+                write!(f, "key {index} of node {node} is outside of the bounds of its parent")
+            }
+            Violation::ChildCountMismatch {
+                node,
+                separators,
+                children,
+            } => write!(
+                f,
+                "node {node} has {children} children for {separators} separators"
+            ),
+            Violation::UnevenDepth {
+                leaf,
+                depth,
+                expected,
+            } => write!(f, "leaf {leaf} is at depth {depth} instead of {expected}"),
+            Violation::Underfull { node } => write!(f, "node {node} is less than a quarter full"),
+            Violation::BrokenLeafLink {
+                leaf,
+                prev,
+                next,
+                expected_prev,
+                expected_next,
+            } => write!(
+                f,
+                "leaf {leaf} links to {prev} and {next} instead of {expected_prev} and {expected_next}"
+            ),
+            Violation::BrokenOverflowChain { leaf, index } => {
+                write!(f, "the overflow chain of value {index} in leaf {leaf} is broken")
+            }
+            Violation::UnreachableBlock { block } => {
+                write!(f, "block {block} is neither used nor free")
+            }
+        }
+    }
+}
+
+/// Result of verifying a tree: what was found and every violation of the tree's invariants
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    pub violations: Vec<Violation>,
+    /// levels of the tree, counting the root and the leaves
+    pub depth: usize,
+    pub inner_nodes: usize,
+    pub leaves: usize,
+    pub entries: usize,
+    pub overflow_blocks: usize,
+    pub free_blocks: usize,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.violations.is_empty()
+    }
+}
+
+impl fmt::Display for VerifyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "depth {}, {} inner nodes, {} leaves, {} entries, {} overflow blocks, {} free blocks",
+            self.depth,
+            self.inner_nodes,
+            self.leaves,
+            self.entries,
+            self.overflow_blocks,
+            self.free_blocks
+        )?;
+
+        match self.violations.len() {
+            0 => writeln!(f, "no violations found"),
+            n => {
+                writeln!(f, "{n} violations found:")?;
+                self.violations
+                    .iter()
+                    .try_for_each(|violation| writeln!(f, "  {violation}"))
+            }
+        }
+    }
+}
+
+/// Walks the blocks of a tree, collecting its violations
+struct Verifier<F> {
+    read_block: F,
+    root: NodeIdent,
+    node_ctr: NodeIdent,
+    block_size: usize,
+    // blocks referenced so far
+    used: BTreeSet<NodeIdent>,
+    // leaves in key order, with their links
+    leaves: Vec<(NodeIdent, NodeIdent, NodeIdent)>,
+    leaf_depth: Option<usize>,
+    report: VerifyReport,
+}
+
+impl<F> Verifier<F>
+where
+    F: FnMut(usize) -> Result<Block, NodeStoreError>,
+{
+    /// marks the block as used, returns false if it can't be followed
+    fn claim(&mut self, from: NodeIdent, block: NodeIdent) -> bool {
+        let violation = if block <= 0 || block > self.node_ctr {
+            Violation::DanglingReference { from, block }
+        } else if !self.used.insert(block) {
+            Violation::DuplicateReference { from, block }
+        } else {
+            return true;
+        };
+
+        self.report.violations.push(violation);
+        false
+    }
+
+    fn read(&mut self, block: NodeIdent) -> Option<Block> {
+        let result = (self.read_block)(block as usize);
+        self.readable(block, result)
+    }
+
+    /// reports the block as unreadable if reading or decoding it failed
+    fn readable<R>(&mut self, block: NodeIdent, result: Result<R, NodeStoreError>) -> Option<R> {
+        match result {
+            Ok(contents) => Some(contents),
+            Err(error) => {
+                self.report
+                    .violations
+                    .push(Violation::UnreadableBlock { block, error });
+                None
+            }
+        }
+    }
+
+    /// checks the node and its subtree, whose keys have to lie within `lower..upper`
+    fn visit<K, T>(&mut self, ident: NodeIdent, depth: usize, lower: Option<&K>, upper: Option<&K>)
+    where
+        K: SearchKey,
+        T: Value,
+    {
+        let Some(block) = self.read(ident) else {
+            return;
+        };
+        let Some(node) = self.readable(ident, Node::<K, T>::from_bytes(ident, &block)) else {
+            return;
+        };
+
+        let is_root = ident == self.root;
+        self.report.depth = self.report.depth.max(depth);
+
+        match node {
+            Node::Leaf(leaf) => {
+                self.report.leaves += 1;
+                self.report.entries += leaf.keys.len();
+
+                let expected = *self.leaf_depth.get_or_insert(depth);
+                if depth != expected {
+                    self.report.violations.push(Violation::UnevenDepth {
+                        leaf: ident,
+                        depth,
+                        expected,
+                    });
+                }
+
+                self.check_keys(ident, &leaf.keys, lower, upper);
+                if !is_root && leaf.underflows(self.block_size) {
+                    self.report
+                        .violations
+                        .push(Violation::Underfull { node: ident });
+                }
+
+                for (index, value) in leaf.values.iter().enumerate() {
+                    if let LeafValue::Overflow { first, len } = value {
+                        self.check_overflow(ident, index, *first, *len);
+                    }
+                }
+
+                self.leaves.push((ident, leaf.prev, leaf.next));
+            }
+            Node::Inner(inner) => {
+                self.report.inner_nodes += 1;
+
+                if inner.children.len() != inner.separators.len() + 1 {
+                    self.report.violations.push(Violation::ChildCountMismatch {
+                        node: ident,
+                        separators: inner.separators.len(),
+                        children: inner.children.len(),
+                    });
+                }
+
+                self.check_keys(ident, &inner.separators, lower, upper);
+                if !is_root && inner.underflows(self.block_size) {
+                    self.report
+                        .violations
+                        .push(Violation::Underfull { node: ident });
+                }
+
+                // every child holds the keys between the separators to its left and right
+                for (i, &child) in inner.children.iter().enumerate() {
+                    if !self.claim(ident, child) {
+                        continue;
+                    }
+
+                    let child_lower = if i == 0 {
+                        lower
+                    } else {
+                        inner.separators.get(i - 1)
+                    };
+                    let child_upper = inner.separators.get(i).or(upper);
+                    self.visit::<K, T>(child, depth + 1, child_lower, child_upper);
+                }
+            }
+        }
+    }
+
+    fn check_keys<K: SearchKey>(
+        &mut self,
+        node: NodeIdent,
+        keys: &[K],
+        lower: Option<&K>,
+        upper: Option<&K>,
+    ) {
+        for (index, key) in keys.iter().enumerate() {
+            if index > 0 && keys[index - 1] >= *key {
+                self.report
+                    .violations
+                    .push(Violation::UnorderedKeys { node, index });
+            }
+
+            if lower.is_some_and(|lower| key < lower) || upper.is_some_and(|upper| key >= upper) {
+                self.report
+                    .violations
+                    .push(Violation::KeyOutOfBounds { node, index });
+            }
+        }
+    }
+
+    fn check_overflow(&mut self, leaf: NodeIdent, index: usize, first: NodeIdent, len: usize) {
+        let mut block = first;
+        let mut from = leaf;
+
+        for _ in 0..len.div_ceil(overflow_payload_size(self.block_size)) {
+            if !self.claim(from, block) {
+                return;
+            }
+            self.report.overflow_blocks += 1;
+
+            let Some(bytes) = self.read(block) else {
+                return;
+            };
+            let Some(next) = self.readable(block, overflow_link(block, &bytes)) else {
+                return;
+            };
+            from = block;
+            block = next;
+        }
+
+        if block != 0 {
+            self.report
+                .violations
+                .push(Violation::BrokenOverflowChain { leaf, index });
+        }
+    }
+
+    fn check_free_list(&mut self, head: NodeIdent) {
+        let mut block = head;
+        let mut from = 0;
+
+        while block != 0 {
+            if !self.claim(from, block) {
+                return;
+            }
+            self.report.free_blocks += 1;
+
+            let Some(bytes) = self.read(block) else {
+                return;
+            };
+            let Some(free) = self.readable(block, FreeBlock::from_bytes(block, &bytes)) else {
+                return;
+            };
+            from = block;
+            block = free.next;
+        }
+    }
+
+    fn check_leaf_links(&mut self) {
+        for (i, &(leaf, prev, next)) in self.leaves.iter().enumerate() {
+            let expected_prev = match i {
+                0 => 0,
+                i => self.leaves[i - 1].0,
+            };
+            let expected_next = self.leaves.get(i + 1).map_or(0, |neighbour| neighbour.0);
+
+            if prev != expected_prev || next != expected_next {
+                self.report.violations.push(Violation::BrokenLeafLink {
+                    leaf,
+                    prev,
+                    next,
+                    expected_prev,
+                    expected_next,
+                });
+            }
+        }
+    }
+}
+
+/// checks the tree described by the metadata, reading its blocks with `read_block`
+pub fn verify<K, T>(
+    metadata: &Metadata,
+    read_block: impl FnMut(usize) -> Result<Block, NodeStoreError>,
+) -> VerifyReport
+where
+    K: SearchKey,
+    T: Value,
+{
+    let mut verifier = Verifier {
+        read_block,
+        root: metadata.root_node,
+        node_ctr: metadata.node_ctr,
+        block_size: metadata.block_size,
+        used: BTreeSet::new(),
+        leaves: vec![],
+        leaf_depth: None,
+        report: VerifyReport::default(),
+    };
+
+    if verifier.claim(0, metadata.root_node) {
+        verifier.visit::<K, T>(metadata.root_node, 1, None, None);
+    }
+    verifier.check_leaf_links();
+    verifier.check_free_list(metadata.free_list_head);
+
+    for block in 1..=metadata.node_ctr {
+        if !verifier.used.contains(&block) {
+            verifier
+                .report
+                .violations
+                .push(Violation::UnreachableBlock { block });
+        }
+    }
+
+    verifier.report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{
+        cache::CacheConfig,
+        file_store::{Durability, FileStore},
+        node::{InnerNode, LeafNode},
+        node_store::NodeStore,
+        test_util::TempFile,
+    };
+
+    fn leaf(keys: &[u32], prev: NodeIdent, next: NodeIdent) -> Block {
+        Node::<u32, u32>::Leaf(LeafNode {
+            keys: keys.to_vec(),
+            values: keys.iter().map(|&key| LeafValue::Inline(key)).collect(),
+            next,
+            prev,
+        })
+        .to_bytes(128)
+    }
+
+    fn inner(separators: &[u32], children: &[NodeIdent]) -> Block {
+        Node::<u32, u32>::Inner(InnerNode {
+            separators: separators.to_vec(),
+            children: children.to_vec(),
+        })
+        .to_bytes(128)
+    }
+
+    /// stores the blocks as blocks 1, 2, ... of a new file and verifies the tree rooted at `root`
+    fn verify_blocks(blocks: Vec<Block>, root: NodeIdent) -> VerifyReport {
+        let file = TempFile::new("verify");
+        let mut store = FileStore::<u32, u32>::new(
+            file.path(),
+            128,
+            Durability::WriteAheadLog,
+            CacheConfig::default(),
+        )
+        .unwrap();
+        for block in blocks {
+            let index = store.allocate_block().unwrap();
+            store.set_block(index as usize, block).unwrap();
+        }
+
+        let metadata = Metadata {
+            block_size: 128,
+            node_ident_size: size_of::<NodeIdent>(),
+            search_key_size: size_of::<u32>(),
+            node_ctr: store.node_ctr(),
+            root_node: root,
+            free_list_head: 0,
+            page_table: 0,
+        };
+        verify::<u32, u32>(&metadata, |index| store.get_block(index))
+    }
+
+    /// a root above two leaves, which is valid as it is
+    fn two_leaves() -> Vec<Block> {
+        vec![
+            inner(&[10], &[2, 3]),
+            leaf(&[1, 2, 3], 0, 3),
+            leaf(&[10, 11, 12], 2, 0),
+        ]
+    }
+
+    fn assert_reports(report: &VerifyReport, found: impl Fn(&Violation) -> bool) {
+        assert!(report.violations.iter().any(found), "{report}");
+    }
+
+    #[test]
+    fn valid_tree_has_no_violations() {
+        let report = verify_blocks(two_leaves(), 1);
+        assert!(report.is_ok(), "{report}");
+        assert_eq!((report.depth, report.leaves, report.entries), (2, 2, 6));
+    }
+
+    #[test]
+    fn unordered_keys_are_reported() {
+        let mut blocks = two_leaves();
+        blocks[1] = leaf(&[1, 3, 2], 0, 3);
+
+        let report = verify_blocks(blocks, 1);
+        assert_reports(&report, |violation| {
+            matches!(violation, Violation::UnorderedKeys { node: 2, index: 2 })
+        });
+    }
+
+    #[test]
+    fn keys_outside_of_separator_bounds_are_reported() {
+        let mut blocks = two_leaves();
+        blocks[2] = leaf(&[9, 11, 12], 2, 0);
+
+        let report = verify_blocks(blocks, 1);
+        assert_reports(&report, |violation| {
+            matches!(violation, Violation::KeyOutOfBounds { node: 3, index: 0 })
+        });
+    }
+
+    #[test]
+    fn uneven_depth_is_reported() {
+        // the second child of the root is an inner node above two more leaves
+        let blocks = vec![
+            inner(&[10], &[2, 3]),
+            leaf(&[1, 2, 3], 0, 4),
+            inner(&[20], &[4, 5]),
+            leaf(&[10, 11, 12], 2, 5),
+            leaf(&[20, 21, 22], 4, 0),
+        ];
+
+        let report = verify_blocks(blocks, 1);
+        assert_reports(&report, |violation| {
+            matches!(
+                violation,
+                Violation::UnevenDepth {
+                    leaf: 4,
+                    depth: 3,
+                    expected: 2
+                }
+            )
+        });
+    }
+
+    #[test]
+    fn duplicate_children_are_reported() {
+        let mut blocks = two_leaves();
+        blocks[0] = inner(&[10], &[2, 2]);
+
+        let report = verify_blocks(blocks, 1);
+        assert_reports(&report, |violation| {
+            matches!(
+                violation,
+                Violation::DuplicateReference { from: 1, block: 2 }
+            )
+        });
+    }
+
+    #[test]
+    fn unreachable_blocks_are_reported() {
+        let mut blocks = two_leaves();
+        blocks.push(leaf(&[100], 0, 0));
+
+        let report = verify_blocks(blocks, 1);
+        assert_reports(&report, |violation| {
+            matches!(violation, Violation::UnreachableBlock { block: 4 })
+        });
+    }
+
+    #[test]
+    fn broken_leaf_links_are_reported() {
+        let mut blocks = two_leaves();
+        blocks[2] = leaf(&[10, 11, 12], 0, 0);
+
+        let report = verify_blocks(blocks, 1);
+        assert_reports(&report, |violation| {
+            matches!(
+                violation,
+                Violation::BrokenLeafLink {
+                    leaf: 3,
+                    prev: 0,
+                    expected_prev: 2,
+                    ..
+                }
+            )
+        });
+    }
+
+    #[test]
+    fn children_pointing_at_free_blocks_are_reported() {
+        // the link of the free block starts with the tag of a leaf
+        let mut blocks = two_leaves();
+        blocks[2] = FreeBlock { next: 257 }.to_bytes(128);
+
+        let report = verify_blocks(blocks, 1);
+        assert_reports(&report, |violation| {
+            matches!(
+                violation,
+                Violation::UnreadableBlock {
+                    block: 3,
+                    error: NodeStoreError::Malformed { block: 3, .. }
+                }
+            )
+        });
+    }
+}
@@ -0,0 +1,185 @@
+use std::{
+    collections::BTreeMap,
+    fs::{File, OpenOptions},
+    io,
+    os::unix::fs::FileExt,
+};
+
+use super::node_store::{Block, NodeStoreError};
+
+/// marks a record holding the image of a single block
+const PAGE_RECORD: u8 = 1;
+/// marks the record closing a commit. Pages of commits without one are ignored on replay
+const COMMIT_RECORD: u8 = 2;
+
+/// kind, block index and length of the block image following it
+const PAGE_HEADER_SIZE: usize = size_of::<u8>() + size_of::<u64>() + size_of::<u32>();
+/// kind, amount of pages in the commit and checksum over their records
+const COMMIT_RECORD_SIZE: usize = size_of::<u8>() + 2 * size_of::<u32>();
+
+/// Write-ahead log kept next to the tree file. Every commit appends the images of all blocks it
+/// changed, followed by a commit record, and is synced before the blocks are written to the tree
+/// file. After a crash the committed images are replayed, so the tree file is always recoverable
+/// to the last commit.
+pub struct Wal {
+    file: File,
+    len: u64,
+}
+
+/// FNV-1a, only used to detect commits that were not completely written
+fn checksum(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c9dc5, |hash, &byte| {
+        (hash ^ byte as u32).wrapping_mul(0x01000193)
+    })
+}
+
+fn read_u32(bytes: &[u8], index: usize) -> u32 {
+    let mut entry = [0; size_of::<u32>()];
+    entry.copy_from_slice(&bytes[index..index + size_of::<u32>()]);
+    u32::from_le_bytes(entry)
+}
+
+impl Wal {
+    /// path of the log belonging to the tree file
+    pub fn path(file_name: &str) -> String {
+        format!("{file_name}.wal")
+    }
+
+    pub fn open(file_name: &str) -> Result<Self, io::Error> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(Wal::path(file_name))?;
+        let len = file.metadata()?.len();
+
+        Ok(Wal { file, len })
+    }
+
+    /// size of the log in bytes
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// appends the blocks as a single commit and waits until it reached the disk
+    pub fn append(&mut self, blocks: &BTreeMap<usize, Block>) -> Result<(), NodeStoreError> {
+        let size: usize = blocks
+            .values()
+            .map(|block| PAGE_HEADER_SIZE + block.len())
+            .sum();
+        let mut records = Vec::with_capacity(size + COMMIT_RECORD_SIZE);
+        for (&index, block) in blocks {
+            records.push(PAGE_RECORD);
+            records.extend_from_slice(&(index as u64).to_le_bytes());
+            records.extend_from_slice(&(block.len() as u32).to_le_bytes());
+            records.extend_from_slice(block);
+        }
+
+        let sum = checksum(&records);
+        records.push(COMMIT_RECORD);
+        records.extend_from_slice(&(blocks.len() as u32).to_le_bytes());
+        records.extend_from_slice(&sum.to_le_bytes());
+
+        self.file
+            .write_all_at(&records, self.len)
+            .and_then(|_| self.file.sync_data())
+            .map_err(|_| NodeStoreError::WriteFailed)?;
+        self.len += records.len() as u64;
+
+        Ok(())
+    }
+
+    /// reads the block images of all complete commits in the order they were written
+    pub fn committed_blocks(&self) -> Result<Vec<(usize, Block)>, io::Error> {
+        let mut log = vec![0; self.len as usize];
+        self.file.read_exact_at(&mut log, 0)?;
+
+        let mut committed = vec![];
+        let mut pending = vec![];
+        let mut commit_start = 0;
+        let mut index = 0;
+
+        while index < log.len() {
+            match log[index] {
+                PAGE_RECORD if index + PAGE_HEADER_SIZE <= log.len() => {
+                    let mut entry = [0; size_of::<u64>()];
+                    entry.copy_from_slice(&log[index + 1..index + 1 + size_of::<u64>()]);
+                    let block_index = u64::from_le_bytes(entry) as usize;
+                    let len = read_u32(&log, index + 1 + size_of::<u64>()) as usize;
+
+                    let start = index + PAGE_HEADER_SIZE;
+                    if start + len > log.len() {
+                        break;
+                    }
+
+                    pending.push((block_index, log[start..start + len].to_vec()));
+                    index = start + len;
+                }
+                COMMIT_RECORD if index + COMMIT_RECORD_SIZE <= log.len() => {
+                    let count = read_u32(&log, index + 1) as usize;
+                    let sum = read_u32(&log, index + 1 + size_of::<u32>());
+
+                    if count != pending.len() || sum != checksum(&log[commit_start..index]) {
+                        break;
+                    }
+
+                    committed.append(&mut pending);
+                    index += COMMIT_RECORD_SIZE;
+                    commit_start = index;
+                }
+                // the rest was torn by a crash while appending
+                _ => break,
+            }
+        }
+
+        Ok(committed)
+    }
+
+    /// empties the log once all of its commits were written to the tree file
+    pub fn truncate(&mut self) -> Result<(), io::Error> {
+        self.file.set_len(0)?;
+        self.file.sync_data()?;
+        self.len = 0;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::test_util::TempFile;
+
+    fn commit(blocks: &[(usize, u8)]) -> BTreeMap<usize, Block> {
+        blocks
+            .iter()
+            .map(|&(index, byte)| (index, vec![byte; 64]))
+            .collect()
+    }
+
+    #[test]
+    fn replay_ignores_incomplete_commits() {
+        let file = TempFile::new("wal");
+        let mut wal = Wal::open(&file.path()).unwrap();
+        wal.append(&commit(&[(1, 1), (2, 2)])).unwrap();
+        wal.append(&commit(&[(1, 3)])).unwrap();
+        let complete = wal.len();
+
+        // a crash while appending leaves part of the last commit behind
+        wal.append(&commit(&[(3, 4), (4, 5)])).unwrap();
+        wal.file.set_len(wal.len() - 10).unwrap();
+        let wal = Wal::open(&file.path()).unwrap();
+
+        let expected = vec![(1, vec![1; 64]), (2, vec![2; 64]), (1, vec![3; 64])];
+        assert_eq!(wal.committed_blocks().unwrap(), expected);
+
+        // a commit whose pages don't match its checksum is ignored as well
+        wal.file.write_all_at(&[0xff], complete - 20).unwrap();
+        assert_eq!(wal.committed_blocks().unwrap(), expected[..2]);
+    }
+}